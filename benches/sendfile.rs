@@ -0,0 +1,80 @@
+//! Compares sending a file with `sendfile` to copying it through userspace buffers,
+//! the two ways binary downloads are served.
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::io::Write;
+
+    use criterion::{Criterion, Throughput};
+    use dock::transfer::{self, DEFAULT_BUFFER_SIZE, TransferType};
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+        runtime::Runtime,
+    };
+
+    const FILE_SIZE: usize = 64 << 20;
+
+    /// Connects to a local listener that discards everything it receives.
+    async fn discarding_socket() -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1 << 20];
+            while peer.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+        });
+        socket
+    }
+
+    pub fn download(c: &mut Criterion) {
+        let runtime = Runtime::new().unwrap();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&vec![b'x'; FILE_SIZE]).unwrap();
+        let path = file.path();
+
+        let mut group = c.benchmark_group("download");
+        group.throughput(Throughput::Bytes(FILE_SIZE as u64));
+        group.sample_size(20);
+
+        let socket = runtime.block_on(discarding_socket());
+        let local = std::fs::File::open(path).unwrap();
+        group.bench_function("sendfile", |b| {
+            b.to_async(&runtime).iter(|| async {
+                transfer::send_file(&local, 0, &socket).await.unwrap();
+            });
+        });
+
+        let socket = runtime.block_on(discarding_socket());
+        let socket = tokio::sync::Mutex::new(socket);
+        group.bench_function("buffered", |b| {
+            b.to_async(&runtime).iter(|| async {
+                let mut reader = tokio::fs::File::open(path).await.unwrap();
+                let mut socket = socket.lock().await;
+                transfer::copy_to_client(
+                    &mut reader,
+                    &mut *socket,
+                    TransferType::Image,
+                    0,
+                    DEFAULT_BUFFER_SIZE,
+                    None,
+                )
+                .await
+                .unwrap();
+            });
+        });
+
+        group.finish();
+    }
+}
+
+#[cfg(target_os = "linux")]
+criterion::criterion_group!(benches, linux::download);
+#[cfg(target_os = "linux")]
+criterion::criterion_main!(benches);
+
+/// `sendfile` is only used on Linux, so there's nothing to compare elsewhere.
+#[cfg(not(target_os = "linux"))]
+fn main() {}
@@ -0,0 +1,39 @@
+use async_trait::async_trait;
+
+use crate::config::{Config, Permissions};
+
+/// Outcome of an authentication attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthResult {
+    Success,
+    UserNotFound,
+    WrongPassword,
+}
+
+/// Source of users for sessions. Implement it to back authentication with a database, LDAP, etc.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Checks user's credentials.
+    async fn authenticate(&self, username: &str, password: &str) -> AuthResult;
+
+    /// Returns user's permissions, or `None` if user is unknown.
+    fn permissions(&self, username: &str) -> Option<Permissions>;
+}
+
+/// Default authenticator backed by the users from configuration file.
+#[async_trait]
+impl Authenticator for Config {
+    async fn authenticate(&self, username: &str, password: &str) -> AuthResult {
+        if !self.check_user(username) {
+            AuthResult::UserNotFound
+        } else if !self.check_password(username, password) {
+            AuthResult::WrongPassword
+        } else {
+            AuthResult::Success
+        }
+    }
+
+    fn permissions(&self, username: &str) -> Option<Permissions> {
+        self.find_user(username).map(|u| u.permissions.clone())
+    }
+}
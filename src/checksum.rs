@@ -0,0 +1,91 @@
+use md5::{Digest, Md5};
+use sha2::Sha256;
+use tokio::io::{self, AsyncRead, AsyncReadExt};
+
+use crate::transfer::DEFAULT_BUFFER_SIZE;
+
+/// Digest algorithms available to `HASH` and the `X*` checksum commands.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Crc32,
+    Md5,
+    #[default]
+    Sha256,
+}
+
+/// Every supported algorithm, in the order they are advertised in `FEAT`.
+pub const HASH_ALGORITHMS: &[HashAlgorithm] = &[
+    HashAlgorithm::Sha256,
+    HashAlgorithm::Md5,
+    HashAlgorithm::Crc32,
+];
+
+impl HashAlgorithm {
+    /// Parses an algorithm name as used by `OPTS HASH`. Returns `None` for unsupported ones.
+    pub fn parse(name: &str) -> Option<Self> {
+        HASH_ALGORITHMS
+            .iter()
+            .copied()
+            .find(|a| a.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Crc32 => "CRC32",
+            HashAlgorithm::Md5 => "MD5",
+            HashAlgorithm::Sha256 => "SHA-256",
+        }
+    }
+}
+
+enum Hasher {
+    Crc32(crc32fast::Hasher),
+    Md5(Md5),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+            HashAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Crc32(h) => h.update(data),
+            Hasher::Md5(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+        }
+    }
+
+    /// Returns the digest as uppercase hex.
+    fn finish(self) -> String {
+        let bytes = match self {
+            Hasher::Crc32(h) => return format!("{:08X}", h.finalize()),
+            Hasher::Md5(h) => h.finalize().to_vec(),
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+        };
+        bytes.iter().map(|b| format!("{b:02X}")).collect()
+    }
+}
+
+/// Computes a digest of everything `reader` produces, reading it in chunks
+/// so files of any size can be hashed.
+pub async fn digest<R>(reader: &mut R, algorithm: HashAlgorithm) -> io::Result<String>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut hasher = Hasher::new(algorithm);
+    let mut buf = vec![0u8; DEFAULT_BUFFER_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finish())
+}
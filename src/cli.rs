@@ -1,4 +1,6 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+use crate::config::Config;
 
 #[derive(Parser)]
 #[command(
@@ -8,6 +10,66 @@ use clap::Parser;
 )]
 pub struct Cli {
     /// The path to the configuration file.
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub config: Option<String>,
+
+    /// The address to listen on. Takes precedence over the configuration file.
+    #[arg(short, long, global = true)]
+    pub address: Option<String>,
+
+    /// The directory to serve. Takes precedence over the configuration file.
+    #[arg(short, long, global = true)]
+    pub root: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Subcommand)]
+pub enum CliCommand {
+    /// Load and validate the configuration without starting the server.
+    Check,
+}
+
+impl Cli {
+    /// Replaces configuration values with the ones given on command line.
+    pub fn apply_overrides(&self, config: &mut Config) {
+        if let Some(address) = &self.address {
+            config.address = address.clone();
+        }
+        if let Some(root) = &self.root {
+            config.root = root.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::config::read_config;
+
+    #[test]
+    fn command_line_beats_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("config.json");
+        let config = serde_json::json!({
+            "address": "127.0.0.1:21",
+            "root": "/srv/ftp",
+            "users": [{ "name": "alice", "password": "secret", "permissions": "All" }],
+        });
+        fs::write(&file, config.to_string()).unwrap();
+        let mut config = read_config(&file.to_string_lossy()).unwrap();
+
+        let cli = Cli::parse_from(["dock", "-a", "0.0.0.0:2121", "-r", "/data"]);
+        cli.apply_overrides(&mut config);
+        assert_eq!(config.address, "0.0.0.0:2121");
+        assert_eq!(config.root, "/data");
+
+        // Settings not given on command line are left as configured.
+        let mut config = read_config(&file.to_string_lossy()).unwrap();
+        Cli::parse_from(["dock", "-r", "/data"]).apply_overrides(&mut config);
+        assert_eq!(config.address, "127.0.0.1:21");
+    }
 }
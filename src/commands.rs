@@ -1,46 +1,161 @@
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Commands {
     User,
     Password,
+    Account,
     WorkingDir,
     ChangeDir,
     Features,
     System,
     Type,
+    Mode,
+    Structure,
     ChangeDirectoryUp,
+    StructureMount,
     List,
+    MachineListDir,
+    MachineListEntry,
     Port,
+    ExtendedPort,
     Size,
+    ModificationTime,
+    SetModifyTime,
+    Hash,
+    CrcChecksum,
+    Md5Checksum,
     Retrive,
     Store,
+    StoreUnique,
+    Allocate,
+    Available,
     Rest,
     Passive,
+    ExtendedPassive,
     Option,
+    Client,
+    Status,
+    Help,
+    Site,
+    ProtectionSize,
+    DataProtection,
+    Reinitialize,
+    Abort,
     Quit,
     Unknown,
 }
 
+/// Every supported command verb, the command it maps to and its help text.
+#[rustfmt::skip]
+pub const COMMAND_TABLE: &[(&str, Commands, &str)] = &[
+    ("USER", Commands::User,              "USER <username>: Begin login."),
+    ("PASS", Commands::Password,          "PASS <password>: Finish login."),
+    ("ACCT", Commands::Account,           "ACCT <account>: Send account information."),
+    ("PWD",  Commands::WorkingDir,        "PWD: Print current directory."),
+    ("XPWD", Commands::WorkingDir,        "XPWD: Print current directory."),
+    ("CWD",  Commands::ChangeDir,         "CWD <path>: Change directory."),
+    ("XCWD", Commands::ChangeDir,         "XCWD <path>: Change directory."),
+    ("CDUP", Commands::ChangeDirectoryUp, "CDUP: Change to parent directory."),
+    ("XCUP", Commands::ChangeDirectoryUp, "XCUP: Change to parent directory."),
+    ("SMNT", Commands::StructureMount,    "SMNT <path>: Mount a file system structure (not implemented)."),
+    ("OPTS", Commands::Option,            "OPTS <option>: Set an option."),
+    ("CLNT", Commands::Client,            "CLNT <name>: Identify client software."),
+    ("LIST", Commands::List,              "LIST [<path>]: List directory."),
+    ("NLST", Commands::List,              "NLST [<path>]: List directory."),
+    ("MLST", Commands::MachineListEntry,  "MLST [<path>]: Print facts about a file or directory."),
+    ("MLSD", Commands::MachineListDir,    "MLSD [<path>]: List directory in machine-readable format."),
+    ("PORT", Commands::Port,              "PORT <h1,h2,h3,h4,p1,p2>: Use active mode."),
+    ("REST", Commands::Rest,              "REST <offset>: Restart next transfer at offset."),
+    ("PASV", Commands::Passive,           "PASV: Use passive mode."),
+    ("EPRT", Commands::ExtendedPort,      "EPRT |<proto>|<address>|<port>|: Use active mode."),
+    ("EPSV", Commands::ExtendedPassive,   "EPSV: Use extended passive mode."),
+    ("RETR", Commands::Retrive,           "RETR <path>: Download a file."),
+    ("STOR", Commands::Store,             "STOR <path>: Upload a file."),
+    ("STOU", Commands::StoreUnique,       "STOU [<name>]: Upload a file under a unique name."),
+    ("ALLO", Commands::Allocate,          "ALLO <size> [R <record>]: Reserve space for an upload."),
+    ("AVBL", Commands::Available,         "AVBL [<path>]: Print free space available for uploads."),
+    ("SIZE", Commands::Size,              "SIZE <path>: Print file size."),
+    ("MDTM", Commands::ModificationTime,  "MDTM <path>: Print file modification time."),
+    ("MFMT", Commands::SetModifyTime,     "MFMT <YYYYMMDDHHMMSS> <path>: Set file modification time."),
+    ("HASH", Commands::Hash,              "HASH <path>: Print file digest, algorithm is chosen with OPTS HASH."),
+    ("XCRC", Commands::CrcChecksum,       "XCRC <path> [<start> [<end>]]: Print CRC32 of a file or its byte range."),
+    ("XMD5", Commands::Md5Checksum,       "XMD5 <path> [<start> [<end>]]: Print MD5 of a file or its byte range."),
+    ("SYST", Commands::System,            "SYST: Print system type."),
+    ("TYPE", Commands::Type,              "TYPE <A|I>: Set transfer type."),
+    ("MODE", Commands::Mode,              "MODE <S>: Set transfer mode."),
+    ("STRU", Commands::Structure,         "STRU <F>: Set file structure."),
+    ("FEAT", Commands::Features,          "FEAT: List server features."),
+    ("STAT", Commands::Status,            "STAT [<path>]: Print session status or list directory."),
+    ("HELP", Commands::Help,              "HELP [<command>]: Print help."),
+    ("SITE", Commands::Site,              "SITE <command> [<args>]: Run a site-specific command."),
+    ("PBSZ", Commands::ProtectionSize,    "PBSZ <size>: Set protection buffer size."),
+    ("PROT", Commands::DataProtection,    "PROT <C|P>: Set data channel protection level."),
+    ("REIN", Commands::Reinitialize,      "REIN: Log out and reset the session."),
+    ("ABOR", Commands::Abort,             "ABOR: Abort the current transfer."),
+    ("QUIT", Commands::Quit,              "QUIT: Close the session."),
+];
+
+/// Every supported `SITE` subcommand and its help text.
+#[rustfmt::skip]
+pub const SITE_COMMAND_TABLE: &[(&str, &str)] = &[
+    ("HELP",  "SITE HELP [<command>]: Print help for SITE commands."),
+    ("UMASK", "SITE UMASK <octal>: Set permission mask for new files."),
+];
+
+/// Commands whose arguments are secrets and must never show up in logs.
+const SECRET_ARGUMENT_COMMANDS: &[&str] = &["PASS", "ACCT"];
+
+/// Returns the argument in a form safe to log. Every log of client input goes through this.
+pub fn redact_argument<'a>(verb: &str, arg: &'a str) -> &'a str {
+    if SECRET_ARGUMENT_COMMANDS
+        .iter()
+        .any(|c| c.eq_ignore_ascii_case(verb))
+    {
+        "***"
+    } else {
+        arg
+    }
+}
+
+/// Returns help text for the given command verb.
+pub fn command_help(verb: &str) -> Option<&'static str> {
+    COMMAND_TABLE
+        .iter()
+        .find(|(v, _, _)| *v == verb)
+        .map(|(_, _, help)| *help)
+}
+
+/// Returns help text for the given `SITE` subcommand.
+pub fn site_command_help(name: &str) -> Option<&'static str> {
+    SITE_COMMAND_TABLE
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, help)| *help)
+}
+
 impl From<String> for Commands {
     fn from(val: String) -> Self {
-        match val.as_str() {
-            "USER" => Commands::User,
-            "PASS" => Commands::Password,
-            "PWD" | "XPWD" => Commands::WorkingDir,
-            "CWD" => Commands::ChangeDir,
-            "CDUP" => Commands::ChangeDirectoryUp,
-            "OPTS" => Commands::Option,
-            "LIST" | "NLST" | "MLST" | "MLSD" => Commands::List,
-            "PORT" => Commands::Port,
-            "REST" => Commands::Rest,
-            "PASV" => Commands::Passive,
-            "RETR" => Commands::Retrive,
-            "STOR" => Commands::Store,
-            "SIZE" => Commands::Size,
-            "SYST" => Commands::System,
-            "TYPE" => Commands::Type,
-            "FEAT" => Commands::Features,
-            "QUIT" => Commands::Quit,
-            _ => Commands::Unknown,
-        }
+        COMMAND_TABLE
+            .iter()
+            .find(|(v, _, _)| *v == val)
+            .map(|(_, c, _)| *c)
+            .unwrap_or(Commands::Unknown)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_secret_arguments() {
+        assert_eq!(redact_argument("PASS", "hunter2"), "***");
+        assert_eq!(redact_argument("pass", "hunter2"), "***");
+        assert_eq!(redact_argument("ACCT", "billing"), "***");
+    }
+
+    #[test]
+    fn keeps_other_arguments() {
+        assert_eq!(redact_argument("USER", "alice"), "alice");
+        assert_eq!(redact_argument("RETR", "file.txt"), "file.txt");
     }
 }
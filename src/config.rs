@@ -1,7 +1,27 @@
-use std::{collections::HashMap, fs};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::ErrorKind,
+    net::{IpAddr, ToSocketAddrs},
+    path::Path,
+};
 
-use anyhow::{Result, anyhow};
+use ipnet::IpNet;
 use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{messages::Message, transfer};
+
+/// Returns an `InvalidValue` error with a formatted reason.
+macro_rules! invalid {
+    ($($arg:tt)*) => {
+        return Err(ConfigError::InvalidValue(format!($($arg)*)))
+    };
+}
+
+const DEFAULT_BANNER: &str = "Dock is welcoming you!";
+/// Lower bound of `max_command_length`, enough for any command with a reasonably long path.
+const MIN_COMMAND_LENGTH: usize = 256;
 
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 pub enum Permissions {
@@ -10,80 +30,753 @@ pub enum Permissions {
     All,
 }
 
-#[derive(Debug, Deserialize, Clone, Default)]
+/// Time zone of modification times shown in `LIST` output.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListingTimezone {
+    #[default]
+    Utc,
+    /// Time zone of the server. Only supported on Unix, elsewhere UTC is used.
+    Local,
+}
+
+impl Permissions {
+    pub fn can_write(&self) -> bool {
+        *self == Permissions::Write || *self == Permissions::All
+    }
+
+    pub fn can_read(&self) -> bool {
+        *self == Permissions::Read || *self == Permissions::All
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub address: String,
     pub users: Vec<User>,
     pub root: String,
+    /// Seconds to wait for active sessions to finish after a shutdown signal.
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// Refuses every write command regardless of user permissions. These are `STOR`, `STOU`
+    /// and `MFMT`, as the server has no commands that delete, rename or create directories.
+    #[serde(default)]
+    pub read_only: bool,
+    /// Lets symlinks inside the root lead outside of it. Such links are refused by default.
+    #[serde(default)]
+    pub follow_symlinks: bool,
+    /// Confines the process to `root` with `chroot(2)` once listeners are bound. Unix only.
+    /// Requires starting as root, so privileges should be dropped afterwards. Paths read while
+    /// serving, like a banner file, are then looked up inside the new root. So is the system
+    /// configuration in `/etc`: unless it's copied there, owner names in listings are shown as
+    /// numeric IDs, and client host names (`resolve_client_hostname`) can't be resolved. The time
+    /// zone and `masquerade_host` are loaded before entering chroot, but the masquerade address is
+    /// only refreshed afterwards if DNS works inside the root.
+    #[serde(default)]
+    pub chroot: bool,
+    /// User to switch to once listeners are bound, e.g. after binding port 21 as root. Unix only.
+    #[serde(default)]
+    pub run_as_user: Option<String>,
+    /// Group to switch to once listeners are bound. Defaults to primary group of `run_as_user`.
+    #[serde(default)]
+    pub run_as_group: Option<String>,
+    /// Makes `STOR` refuse to replace existing files. Can be overridden per user.
+    #[serde(default)]
+    pub no_overwrite: bool,
+    /// Client networks allowed to connect. Empty list allows everyone.
+    #[serde(default)]
+    pub allow_ips: Vec<IpNet>,
+    /// Client networks refused to connect. Checked before `allow_ips`.
+    #[serde(default)]
+    pub deny_ips: Vec<IpNet>,
+    /// Maximum connections accepted from one address per minute. `0` disables the limit.
+    #[serde(default)]
+    pub max_connections_per_minute: u32,
+    /// Failed `PASS` attempts after which the session is closed. `0` disables the limit.
+    #[serde(default = "default_max_login_attempts")]
+    pub max_login_attempts: u32,
+    /// Address passive data listeners are bound to instead of the one client has connected to,
+    /// for servers with several interfaces. `PASV` advertises it unless it's unspecified (`0.0.0.0`).
+    /// `EPSV` replies carry no address, so with `EPSV` it should match the control address or be unspecified.
+    #[serde(default)]
+    pub data_address: Option<IpAddr>,
+    /// Host name or IPv4 address advertised in `PASV` replies instead of the local address, for servers behind NAT.
+    #[serde(default)]
+    pub masquerade_host: Option<String>,
+    /// Doesn't require `masquerade_host` to be resolvable at startup.
+    #[serde(default)]
+    pub masquerade_defer_resolution: bool,
+    /// Refuses active mode (`PORT`, `EPRT`), so the server never connects to clients.
+    #[serde(default)]
+    pub passive_only: bool,
+    /// Refuses passive mode (`PASV`, `EPSV`), e.g. when no ports can be opened for data connections.
+    #[serde(default)]
+    pub active_only: bool,
+    /// Allows `PORT` and `EPRT` to point at addresses other than the client's own.
+    #[serde(default)]
+    pub allow_foreign_data_addresses: bool,
+    /// Maximum size of an uploaded file in bytes. `0` disables the limit.
+    #[serde(default)]
+    pub max_upload_size: u64,
+    /// Size of buffer used for copying data of transfers, in bytes.
+    #[serde(default = "default_transfer_buffer_size")]
+    pub transfer_buffer_size: usize,
+    /// Longest command line accepted from clients, in bytes. Longer lines are rejected.
+    #[serde(default = "default_max_command_length")]
+    pub max_command_length: usize,
+    /// Time zone of dates in `LIST` output, `utc` or `local`. `MDTM` and `MLSD` always use UTC.
+    #[serde(default)]
+    pub listing_timezone: ListingTimezone,
+    /// Greeting sent to clients before login. Either text or a path to a text file.
+    #[serde(default)]
+    pub banner: Option<String>,
+    /// Replacements for reply texts, e.g. translations. Keys are names of `Message` variants in snake_case.
+    #[serde(default)]
+    pub messages: HashMap<Message, String>,
+    /// Reply to `SYST`, e.g. `Windows_NT` for clients that need it. Same as setting `system_type`
+    /// in `messages`, which takes precedence if both are set.
+    #[serde(default)]
+    pub system_type: Option<String>,
+    /// Address of the HTTP listener serving Prometheus metrics. Requires the `metrics` feature.
+    #[serde(default)]
+    pub metrics_address: Option<String>,
+    /// Address of a TCP listener that answers connections with a `200 OK` line and closes them, for liveness probes.
+    #[serde(default)]
+    pub healthcheck_address: Option<String>,
+    /// Expects a PROXY protocol v1 header on every connection, as sent by load balancers,
+    /// and takes client address from it. Connections without a valid header are dropped.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Looks up host names of clients and adds them to log lines of their sessions.
+    #[serde(default)]
+    pub resolve_client_hostname: bool,
     #[serde(skip, default)]
     pub users_map: HashMap<String, User>,
 }
 
+impl Default for Config {
+    /// Same defaults a configuration file gets for settings it leaves out.
+    fn default() -> Self {
+        Self {
+            address: String::new(),
+            users: Vec::new(),
+            root: String::new(),
+            shutdown_timeout: default_shutdown_timeout(),
+            read_only: false,
+            follow_symlinks: false,
+            chroot: false,
+            run_as_user: None,
+            run_as_group: None,
+            no_overwrite: false,
+            allow_ips: Vec::new(),
+            deny_ips: Vec::new(),
+            max_connections_per_minute: 0,
+            max_login_attempts: default_max_login_attempts(),
+            data_address: None,
+            masquerade_host: None,
+            masquerade_defer_resolution: false,
+            passive_only: false,
+            active_only: false,
+            allow_foreign_data_addresses: false,
+            max_upload_size: 0,
+            transfer_buffer_size: default_transfer_buffer_size(),
+            max_command_length: default_max_command_length(),
+            listing_timezone: ListingTimezone::default(),
+            banner: None,
+            messages: HashMap::new(),
+            system_type: None,
+            metrics_address: None,
+            healthcheck_address: None,
+            proxy_protocol: false,
+            resolve_client_hostname: false,
+            users_map: HashMap::new(),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct User {
     pub name: String,
     pub password: String,
     pub permissions: Permissions,
+    /// Maximum download speed in bytes per second. `0` means unlimited.
+    #[serde(default)]
+    pub download_rate_limit: u64,
+    /// Maximum upload speed in bytes per second. `0` means unlimited.
+    #[serde(default)]
+    pub upload_rate_limit: u64,
+    /// Maximum size of the served directory the user may upload into. `0` means unlimited.
+    /// The limit is on the directory as a whole, not per user: files of every user count
+    /// against it, as files don't record who has uploaded them.
+    #[serde(default)]
+    pub quota_bytes: u64,
+    /// Directory the user lands in after login, relative to the served root.
+    #[serde(default)]
+    pub start_dir: Option<String>,
+    /// Overrides the global `no_overwrite` setting for this user.
+    #[serde(default)]
+    pub no_overwrite: Option<bool>,
 }
 
-#[derive(Debug)]
+impl User {
+    /// Creates a user without any limits.
+    pub fn new(
+        name: impl Into<String>,
+        password: impl Into<String>,
+        permissions: Permissions,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            password: password.into(),
+            permissions,
+            download_rate_limit: 0,
+            upload_rate_limit: 0,
+            quota_bytes: 0,
+            start_dir: None,
+            no_overwrite: None,
+        }
+    }
+}
+
+/// Builds `Config` in code, starting from the same defaults a configuration file gets.
+/// Lookup map of users is built and settings are validated by `build`.
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    /// Adds a user.
+    pub fn user(mut self, user: User) -> Self {
+        self.config.users.push(user);
+        self
+    }
+
+    /// Refuses every write command regardless of user permissions.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.config.read_only = read_only;
+        self
+    }
+
+    /// Sets seconds to wait for active sessions to finish after a shutdown signal.
+    pub fn shutdown_timeout(mut self, seconds: u64) -> Self {
+        self.config.shutdown_timeout = seconds;
+        self
+    }
+
+    /// Sets failed logins after which the connection is closed. `0` means unlimited.
+    pub fn max_login_attempts(mut self, attempts: u32) -> Self {
+        self.config.max_login_attempts = attempts;
+        self
+    }
+
+    /// Sets the greeting sent to clients before login.
+    pub fn banner(mut self, banner: impl Into<String>) -> Self {
+        self.config.banner = Some(banner.into());
+        self
+    }
+
+    /// Changes any other setting directly.
+    pub fn configure(mut self, f: impl FnOnce(&mut Config)) -> Self {
+        f(&mut self.config);
+        self
+    }
+
+    pub fn build(mut self) -> Result<Config, ConfigError> {
+        self.config.build_users_map();
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
 pub enum ConfigError {
+    #[error("user not found")]
     UserNotFound,
+
+    #[error("wrong password")]
     WrongPassword,
+
+    #[error("configuration file '{0}' does not exist")]
+    FileNotFound(String),
+
+    #[error("no permission to read configuration file '{0}'")]
+    PermissionDenied(String),
+
+    #[error("cannot read configuration file: {0}")]
+    ReadFailed(String),
+
+    #[error("bad config format: {0}")]
+    ParseError(String),
+
+    #[error("environment variable '{0}' used in config is not set")]
+    UndefinedVariable(String),
+
+    #[error("unclosed variable reference in config field '{0}'")]
+    UnclosedVariable(String),
+
+    #[error("root directory '{0}' does not exist")]
+    RootNotFound(String),
+
+    #[error("root '{0}' is not a directory")]
+    RootNotADirectory(String),
+
+    #[error("at least one user must be configured")]
+    NoUsers,
+
+    #[error("user '{0}' is configured more than once")]
+    DuplicateUser(String),
+
+    #[error("{0}")]
+    InvalidValue(String),
 }
 
 impl Config {
-    /// Checks if user exists.
-    pub fn check_user(&self, username: &str) -> bool {
+    /// Starts building configuration in code, as an alternative to loading it from file.
+    pub fn builder(address: impl Into<String>, root: impl Into<String>) -> ConfigBuilder {
+        ConfigBuilder {
+            config: Config {
+                address: address.into(),
+                root: root.into(),
+                ..Config::default()
+            },
+        }
+    }
+
+    /// Checks that configuration is usable, so mistakes are reported at startup.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let resolved = self
+            .address
+            .to_socket_addrs()
+            .map(|mut a| a.next().is_some());
+        if !resolved.unwrap_or(false) {
+            invalid!("address '{}' is not a valid socket address", self.address);
+        }
+
+        let root = Path::new(&self.root);
+        if !root.exists() {
+            return Err(ConfigError::RootNotFound(self.root.clone()));
+        }
+        if !root.is_dir() {
+            return Err(ConfigError::RootNotADirectory(self.root.clone()));
+        }
+
+        if self.users.is_empty() {
+            return Err(ConfigError::NoUsers);
+        }
+        if self.users.iter().any(|u| u.name.is_empty()) {
+            invalid!("user name cannot be empty");
+        }
+        let mut names = HashSet::new();
+        if let Some(user) = self.users.iter().find(|u| !names.insert(u.name.as_str())) {
+            return Err(ConfigError::DuplicateUser(user.name.clone()));
+        }
+
+        if self.transfer_buffer_size == 0 {
+            invalid!("transfer_buffer_size must be greater than zero");
+        }
+        if self.max_command_length < MIN_COMMAND_LENGTH {
+            invalid!("max_command_length must be at least {MIN_COMMAND_LENGTH}");
+        }
+
+        if let Some(host) = &self.masquerade_host
+            && !self.masquerade_defer_resolution
+        {
+            let resolved = (host.as_str(), 0)
+                .to_socket_addrs()
+                .map(|mut a| a.any(|a| a.is_ipv4()));
+            if !resolved.unwrap_or(false) {
+                invalid!("masquerade host '{host}' cannot be resolved to an IPv4 address");
+            }
+        }
+
+        if let Some(ip) = self.data_address
+            && let Err(e) = std::net::TcpListener::bind((ip, 0))
+        {
+            invalid!("data_address '{ip}' cannot be used for listening: {e}");
+        }
+
+        if self.passive_only && self.active_only {
+            invalid!("passive_only and active_only cannot be both enabled");
+        }
+
+        if self.chroot && !cfg!(unix) {
+            invalid!("chroot is only supported on Unix");
+        }
+        if (self.run_as_user.is_some() || self.run_as_group.is_some()) && !cfg!(unix) {
+            invalid!("run_as_user and run_as_group are only supported on Unix");
+        }
+
+        Ok(())
+    }
+
+    /// Finds user by name. Falls back to the users list when the map wasn't built,
+    /// e.g. when `Config` was constructed in code rather than loaded from file.
+    pub fn find_user(&self, username: &str) -> Option<&User> {
         if !self.users_map.is_empty() {
-            self.users_map.contains_key(username)
+            self.users_map.get(username)
         } else {
-            self.users.iter().any(|f| f.name == username)
+            self.users.iter().find(|u| u.name == username)
         }
     }
 
+    /// Builds the lookup map from the users list.
+    pub fn build_users_map(&mut self) {
+        self.users_map = self
+            .users
+            .iter()
+            .cloned()
+            .map(|u| (u.name.clone(), u))
+            .collect();
+    }
+
+    /// Checks if user exists.
+    pub fn check_user(&self, username: &str) -> bool {
+        self.find_user(username).is_some()
+    }
+
     // Checks if user's password matches.
     pub fn check_password(&self, username: &str, password: &str) -> bool {
-        if !self.users_map.is_empty() {
-            self.users_map
-                .get(username)
-                .map(|u| u.password == password)
-                .unwrap_or(false)
-        } else {
-            self.users
-                .iter()
-                .any(|f| f.name == username && f.password == password)
-        }
+        self.find_user(username)
+            .is_some_and(|u| u.password == password)
     }
 
     /// Checks if user has access to write.
     pub fn can_user_write(&self, username: &str) -> bool {
-        if let Some(user) = self.users_map.get(username) {
-            user.permissions == Permissions::Write || user.permissions == Permissions::All
-        } else {
-            false
-        }
+        self.find_user(username)
+            .is_some_and(|u| u.permissions.can_write())
     }
 
     /// Checks if user has access to read.
     pub fn can_user_read(&self, username: &str) -> bool {
-        if let Some(user) = self.users_map.get(username) {
-            user.permissions == Permissions::Read || user.permissions == Permissions::All
+        self.find_user(username)
+            .is_some_and(|u| u.permissions.can_read())
+    }
+
+    /// Checks if client with given address may connect.
+    pub fn is_ip_allowed(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.deny_ips.iter().any(|n| n.contains(&ip)) {
+            return false;
+        }
+        self.allow_ips.is_empty() || self.allow_ips.iter().any(|n| n.contains(&ip))
+    }
+
+    /// Returns lines of the greeting, reading them from file if `banner` points to one.
+    pub fn banner_lines(&self) -> Vec<String> {
+        let Some(banner) = self.banner.as_deref() else {
+            return vec![String::from(DEFAULT_BANNER)];
+        };
+
+        let text = if Path::new(banner).is_file() {
+            fs::read_to_string(banner).unwrap_or_default()
         } else {
-            false
+            banner.to_owned()
+        };
+        let lines: Vec<String> = text.lines().map(|l| l.trim_end().to_owned()).collect();
+        if lines.iter().all(|l| l.is_empty()) {
+            return vec![String::from(DEFAULT_BANNER)];
+        }
+        lines
+    }
+
+    /// Returns text of a reply, taking replacements from configuration into account.
+    pub fn message(&self, message: Message) -> &str {
+        if let Some(text) = self.messages.get(&message) {
+            return text;
+        }
+        match (message, &self.system_type) {
+            (Message::SystemType, Some(system_type)) => system_type,
+            _ => message.default_text(),
+        }
+    }
+
+    /// Returns user's download speed limit in bytes per second.
+    pub fn download_rate_limit(&self, username: &str) -> u64 {
+        self.find_user(username)
+            .map(|u| u.download_rate_limit)
+            .unwrap_or(0)
+    }
+
+    /// Returns user's upload speed limit in bytes per second.
+    pub fn upload_rate_limit(&self, username: &str) -> u64 {
+        self.find_user(username)
+            .map(|u| u.upload_rate_limit)
+            .unwrap_or(0)
+    }
+
+    /// Returns user's storage quota in bytes.
+    pub fn quota_bytes(&self, username: &str) -> u64 {
+        self.find_user(username).map(|u| u.quota_bytes).unwrap_or(0)
+    }
+
+    /// Checks if user's uploads may not replace existing files.
+    pub fn no_overwrite(&self, username: &str) -> bool {
+        self.find_user(username)
+            .and_then(|u| u.no_overwrite)
+            .unwrap_or(self.no_overwrite)
+    }
+
+    /// Returns directory the user should start in, if one is configured.
+    pub fn start_dir(&self, username: &str) -> Option<&str> {
+        self.find_user(username)?.start_dir.as_deref()
+    }
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_max_login_attempts() -> u32 {
+    5
+}
+
+fn default_transfer_buffer_size() -> usize {
+    transfer::DEFAULT_BUFFER_SIZE
+}
+
+fn default_max_command_length() -> usize {
+    4096
+}
+
+/// Reads configuration file without validating it, so values can still be overridden.
+pub fn read_config(path: &str) -> Result<Config, ConfigError> {
+    let content = fs::read_to_string(path).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => ConfigError::FileNotFound(path.to_owned()),
+        ErrorKind::PermissionDenied => ConfigError::PermissionDenied(path.to_owned()),
+        _ => ConfigError::ReadFailed(e.to_string()),
+    })?;
+    let mut value = serde_json::from_str::<serde_json::Value>(&content)
+        .map_err(|e| ConfigError::ParseError(e.to_string()))?;
+    expand_env_values(&mut value, "")?;
+    let mut config = serde_json::from_value::<Config>(value)
+        .map_err(|e| ConfigError::ParseError(e.to_string()))?;
+    config.build_users_map();
+    Ok(config)
+}
+
+/// Expands environment variables in every string of the configuration, so secrets don't have to be stored in it.
+/// `field` is the path of the value, e.g. `users[0].password`, used in errors instead of the value itself.
+fn expand_env_values(value: &mut serde_json::Value, field: &str) -> Result<(), ConfigError> {
+    match value {
+        serde_json::Value::String(s) => *s = expand_env(s, field)?,
+        serde_json::Value::Array(values) => {
+            for (i, value) in values.iter_mut().enumerate() {
+                expand_env_values(value, &format!("{field}[{i}]"))?;
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                let field = if field.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{field}.{key}")
+                };
+                expand_env_values(value, &field)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replaces `${NAME}` with value of the environment variable. `$${` stands for a literal `${`.
+/// Errors name the `field` rather than quote the value, which may hold a secret.
+fn expand_env(s: &str, field: &str) -> Result<String, ConfigError> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            out.push_str(&rest[..start - 1]);
+            out.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
         }
+        out.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find('}') else {
+            return Err(ConfigError::UnclosedVariable(field.to_owned()));
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        let value =
+            std::env::var(name).map_err(|_| ConfigError::UndefinedVariable(name.to_owned()))?;
+        out.push_str(&value);
+        rest = &rest[start + 3 + len..];
     }
+    out.push_str(rest);
+    Ok(out)
 }
 
-pub fn load_config(path: &str) -> Result<Config> {
-    let content = fs::read_to_string(path).map_err(|_| anyhow!("a file system error occurred."))?;
-    let mut config =
-        serde_json::from_str::<Config>(&content).map_err(|e| anyhow!("bad config format: {e}"))?;
-    config.users_map = config
-        .users
-        .iter()
-        .cloned()
-        .map(|u| (u.name.clone(), u))
-        .collect();
+pub fn load_config(path: &str) -> Result<Config, ConfigError> {
+    let config = read_config(path)?;
+    config.validate()?;
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder(root: &Path) -> ConfigBuilder {
+        Config::builder("127.0.0.1:0", root.to_string_lossy()).user(User::new(
+            "alice",
+            "secret",
+            Permissions::All,
+        ))
+    }
+
+    #[test]
+    fn expands_defined_variables() {
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(expand_env("${PATH}", "root").unwrap(), path);
+        assert_eq!(
+            expand_env("a:${PATH}:b", "root").unwrap(),
+            format!("a:{path}:b")
+        );
+        assert_eq!(expand_env("no variables", "root").unwrap(), "no variables");
+    }
+
+    #[test]
+    fn keeps_escaped_references() {
+        assert_eq!(expand_env("$${PATH}", "root").unwrap(), "${PATH}");
+    }
+
+    #[test]
+    fn fails_on_undefined_variables() {
+        assert_eq!(
+            expand_env("${DOCK_TEST_SURELY_UNDEFINED}", "root"),
+            Err(ConfigError::UndefinedVariable(String::from(
+                "DOCK_TEST_SURELY_UNDEFINED"
+            )))
+        );
+    }
+
+    #[test]
+    fn unclosed_references_name_only_the_field() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("config.json");
+        let config = serde_json::json!({
+            "address": "127.0.0.1:0",
+            "root": dir.path(),
+            "users": [{ "name": "alice", "password": "hunter2${", "permissions": "All" }],
+        });
+        fs::write(&file, config.to_string()).unwrap();
+
+        let error = read_config(&file.to_string_lossy()).unwrap_err();
+        assert_eq!(
+            error,
+            ConfigError::UnclosedVariable(String::from("users[0].password"))
+        );
+        assert!(!error.to_string().contains("hunter2"));
+    }
+
+    #[test]
+    fn rejects_duplicate_users() {
+        let root = tempfile::tempdir().unwrap();
+        let result = builder(root.path())
+            .user(User::new("alice", "other", Permissions::Read))
+            .build();
+        assert_eq!(
+            result.unwrap_err(),
+            ConfigError::DuplicateUser(String::from("alice"))
+        );
+    }
+
+    #[test]
+    fn rejects_missing_users() {
+        let root = tempfile::tempdir().unwrap();
+        let result = Config::builder("127.0.0.1:0", root.path().to_string_lossy()).build();
+        assert_eq!(result.unwrap_err(), ConfigError::NoUsers);
+    }
+
+    #[test]
+    fn rejects_bad_roots() {
+        let root = tempfile::tempdir().unwrap();
+        let missing = root.path().join("missing");
+        let result = builder(&missing).build();
+        assert!(matches!(result, Err(ConfigError::RootNotFound(_))));
+
+        let file = root.path().join("file");
+        fs::write(&file, "").unwrap();
+        let result = builder(&file).build();
+        assert!(matches!(result, Err(ConfigError::RootNotADirectory(_))));
+    }
+
+    #[test]
+    fn rejects_both_data_modes_disabled() {
+        let root = tempfile::tempdir().unwrap();
+        let result = builder(root.path())
+            .configure(|c| {
+                c.passive_only = true;
+                c.active_only = true;
+            })
+            .build();
+        assert!(matches!(result, Err(ConfigError::InvalidValue(_))));
+    }
+
+    #[test]
+    fn reports_unreadable_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.json");
+        let missing = missing.to_string_lossy();
+        assert_eq!(
+            read_config(&missing).unwrap_err(),
+            ConfigError::FileNotFound(missing.to_string())
+        );
+
+        let broken = dir.path().join("broken.json");
+        fs::write(&broken, "{ not json").unwrap();
+        assert!(matches!(
+            read_config(&broken.to_string_lossy()),
+            Err(ConfigError::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn loads_valid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("config.json");
+        let config = serde_json::json!({
+            "address": "127.0.0.1:0",
+            "root": dir.path(),
+            "users": [{ "name": "alice", "password": "secret", "permissions": "All" }],
+        });
+        fs::write(&file, config.to_string()).unwrap();
+
+        let config = load_config(&file.to_string_lossy()).unwrap();
+        assert!(config.check_password("alice", "secret"));
+        assert_eq!(config.max_command_length, default_max_command_length());
+    }
+
+    #[test]
+    fn system_type_replaces_syst_reply() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = builder(dir.path())
+            .configure(|c| c.system_type = Some(String::from("Windows_NT")))
+            .build()
+            .unwrap();
+        assert_eq!(config.message(Message::SystemType), "Windows_NT");
+
+        // Message table is the more general override, so it wins.
+        let config = builder(dir.path())
+            .configure(|c| {
+                c.system_type = Some(String::from("Windows_NT"));
+                c.messages
+                    .insert(Message::SystemType, String::from("MACOS"));
+            })
+            .build()
+            .unwrap();
+        assert_eq!(config.message(Message::SystemType), "MACOS");
+    }
+
+    #[test]
+    fn defaults_match_missing_settings() {
+        let from_file: Config =
+            serde_json::from_value(serde_json::json!({ "address": "", "users": [], "root": "" }))
+                .unwrap();
+        let default = Config::default();
+        assert_eq!(default.shutdown_timeout, from_file.shutdown_timeout);
+        assert_eq!(default.max_login_attempts, from_file.max_login_attempts);
+        assert_eq!(default.transfer_buffer_size, from_file.transfer_buffer_size);
+        assert_eq!(default.max_command_length, from_file.max_command_length);
+    }
+}
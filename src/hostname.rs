@@ -0,0 +1,46 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::{task, time};
+
+/// Looks up host names of client addresses for logs. Results, including failures,
+/// are cached for a while, so clients reconnecting often don't cause repeated lookups.
+#[derive(Debug, Default)]
+pub struct HostnameResolver {
+    cache: Mutex<HashMap<IpAddr, (Option<String>, Instant)>>,
+}
+
+impl HostnameResolver {
+    const CACHE_TTL: Duration = Duration::from_secs(300);
+    const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns host name of the address, or `None` if it has none or the lookup timed out.
+    pub async fn resolve(&self, ip: IpAddr) -> Option<String> {
+        if let Some((hostname, resolved_at)) = self.cache.lock().unwrap().get(&ip)
+            && resolved_at.elapsed() < Self::CACHE_TTL
+        {
+            return hostname.clone();
+        }
+
+        // System resolver blocks, so it runs on a blocking thread. A lookup that times out
+        // keeps running there, but nobody waits for it.
+        let lookup = task::spawn_blocking(move || dns_lookup::lookup_addr(&ip));
+        let hostname = match time::timeout(Self::LOOKUP_TIMEOUT, lookup).await {
+            Ok(Ok(Ok(hostname))) => Some(hostname),
+            _ => None,
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (_, resolved_at)| resolved_at.elapsed() < Self::CACHE_TTL);
+        cache.insert(ip, (hostname.clone(), Instant::now()));
+        hostname
+    }
+}
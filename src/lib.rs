@@ -1,5 +1,17 @@
+pub mod auth;
+pub mod checksum;
 pub mod cli;
 pub mod commands;
 pub mod config;
+pub mod hostname;
+pub mod masquerade;
+pub mod messages;
+pub mod metrics;
+pub mod observer;
+pub mod pattern;
+pub mod proxy;
 pub mod server;
 pub mod session;
+pub mod transfer;
+pub mod usage;
+pub mod vfs;
@@ -1,22 +1,61 @@
-use std::process::exit;
+use std::{fs, process::exit};
 
 use clap::Parser;
-use dock::{cli::Cli, config::load_config, server::Server};
+use dock::{
+    cli::{Cli, CliCommand},
+    config::{Config, ConfigError, read_config},
+    server::Server,
+};
 
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
-    let config_path = cli.config.unwrap_or(String::from("config.json"));
-    let config = match load_config(&config_path) {
+    let config_path = cli.config.clone().unwrap_or(String::from("config.json"));
+    let mut config = match read_config(&config_path) {
         Ok(c) => c,
         Err(e) => {
             eprintln!("failed to load configuration: {e}");
-            exit(1);
+            exit(exit_code(&e));
         }
     };
+    cli.apply_overrides(&mut config);
+    if let Err(e) = config.validate() {
+        eprintln!("failed to load configuration: {e}");
+        exit(exit_code(&e));
+    }
+
+    if let Some(CliCommand::Check) = cli.command {
+        print_summary(&config_path, &config);
+        return;
+    }
 
     let server = Server::new(config);
     if let Err(e) = server.start_server().await {
         eprintln!("Server error occurred: {e}");
     }
 }
+
+/// Picks an exit code from `sysexits.h`, so scripts can tell a missing file from a bad one.
+fn exit_code(error: &ConfigError) -> i32 {
+    match error {
+        ConfigError::FileNotFound(_) => 66,
+        ConfigError::PermissionDenied(_) => 77,
+        ConfigError::ReadFailed(_) => 74,
+        ConfigError::ParseError(_) => 65,
+        _ => 78,
+    }
+}
+
+fn print_summary(path: &str, config: &Config) {
+    let root = fs::canonicalize(&config.root)
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|_| config.root.clone());
+
+    println!("Configuration '{path}' is valid.");
+    println!("Address: {}", config.address);
+    println!("Root: {root}");
+    println!("Users:");
+    for user in &config.users {
+        println!("  {} ({:?})", user.name, user.permissions);
+    }
+}
@@ -0,0 +1,62 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::net::lookup_host;
+use tracing::warn;
+
+/// Resolves the address advertised in `PASV` replies, e.g. a dynamic DNS name of a server behind NAT.
+/// Resolution is shared between sessions and refreshed periodically.
+#[derive(Debug)]
+pub struct MasqueradeResolver {
+    host: String,
+    cached: Mutex<Option<(Ipv4Addr, Instant)>>,
+}
+
+impl MasqueradeResolver {
+    const REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+    pub fn new(host: impl Into<String>) -> Self {
+        Self {
+            host: host.into(),
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Returns the IPv4 address of the host. When it can't be resolved, the last known address is used.
+    pub async fn resolve(&self) -> Option<Ipv4Addr> {
+        let cached = *self.cached.lock().unwrap();
+        if let Some((ip, resolved_at)) = cached
+            && resolved_at.elapsed() < Self::REFRESH_INTERVAL
+        {
+            return Some(ip);
+        }
+
+        match lookup_ipv4(&self.host).await {
+            Some(ip) => {
+                *self.cached.lock().unwrap() = Some((ip, Instant::now()));
+                Some(ip)
+            }
+            None => {
+                warn!(host=%self.host, "Failed to resolve masquerade host.");
+                cached.map(|(ip, _)| ip)
+            }
+        }
+    }
+}
+
+async fn lookup_ipv4(host: &str) -> Option<Ipv4Addr> {
+    if let Ok(ip) = host.parse::<Ipv4Addr>() {
+        return Some(ip);
+    }
+
+    lookup_host((host, 0))
+        .await
+        .ok()?
+        .find_map(|a| match a.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        })
+}
@@ -0,0 +1,190 @@
+use serde::Deserialize;
+
+/// Fixed texts of replies. Each of them can be replaced in configuration by its snake_case name,
+/// e.g. `"login_success": "Willkommen!"`, to translate or customize what clients see.
+/// Replies with variable parts, like paths or sizes, are built where they are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Message {
+    ServerShutdown,
+    RestNotSupported,
+    AlreadyLoggedIn,
+    UsernameRequired,
+    NeedPassword,
+    PasswordRequired,
+    TooManyFailedLogins,
+    AuthorizationFailed,
+    LoginSuccess,
+    AccountSuperfluous,
+    PathRequired,
+    NotADirectory,
+    PermissionDenied,
+    DirectoryChanged,
+    PathNotFound,
+    ChangeDirectoryFailed,
+    ClientNameRequired,
+    Noted,
+    ArgumentRequired,
+    Utf8Enabled,
+    Utf8Disabled,
+    Utf8Usage,
+    UnknownAlgorithm,
+    UnknownOption,
+    CantOpenDataConnection,
+    LocalError,
+    TransferComplete,
+    SecurityNotCompleted,
+    SessionReinitialized,
+    NoTransferToAbort,
+    Bye,
+    InvalidUmask,
+    UnknownSiteCommand,
+    NotImplemented,
+    UnknownCommand,
+    TooManyUnknownCommands,
+    /// Reply to `SYST`, also settable with the top-level `system_type` option. It's the same on
+    /// every platform, as clients use it to pick a listing parser and `LIST` output is always in Unix format.
+    SystemType,
+    TypeNotSupported,
+    UnsupportedMode,
+    UnknownMode,
+    UnsupportedStructure,
+    UnknownStructure,
+    NotAFile,
+    ModificationTimeUnavailable,
+    TimestampAndPathRequired,
+    InvalidTimestamp,
+    UseEprt,
+    AddressRequired,
+    SyntaxError,
+    InvalidPort,
+    IllegalPortAddress,
+    PortSuccess,
+    UseEpsv,
+    ProtocolNotSupported,
+    EprtSuccess,
+    EpsvAllSuccess,
+    RestartAccepted,
+    InvalidRestartPosition,
+    DownloadComplete,
+    FileNameNotAllowed,
+    FileExists,
+    SizeRequired,
+    AllocationAccepted,
+    FileUnavailable,
+    ListFailed,
+    SetModificationTimeFailed,
+    LoginRequired,
+    ActiveModeDisabled,
+    PassiveModeDisabled,
+    DataChannelRequired,
+    NoReadPermission,
+    ServerReadOnly,
+    NoWritePermission,
+    RootUnavailable,
+    TransferAborted,
+    InvalidByteRange,
+    StorageExceeded,
+    FileTooLarge,
+    OpeningListing,
+    OpeningDownload,
+    /// Preliminary reply to `RETR` after `REST`. `{offset}` is replaced with the restart position.
+    RestartingDownload,
+    OpeningUpload,
+    LineTooLong,
+    AbortSuccessful,
+    BadSequence,
+}
+
+impl Message {
+    /// Returns the built-in English text.
+    pub fn default_text(self) -> &'static str {
+        match self {
+            Message::ServerShutdown => "Server shutting down.",
+            Message::RestNotSupported => "REST not supported for this command.",
+            Message::AlreadyLoggedIn => "Already logged in.",
+            Message::UsernameRequired => "Username is required.",
+            Message::NeedPassword => "Password is required",
+            Message::PasswordRequired => "Password is required",
+            Message::TooManyFailedLogins => "Too many failed login attempts.",
+            Message::AuthorizationFailed => "Authorization failed.",
+            Message::LoginSuccess => "Login success.",
+            Message::AccountSuperfluous => "Command not implemented, superfluous at this site.",
+            Message::PathRequired => "Path is required",
+            Message::NotADirectory => "Not a directory.",
+            Message::PermissionDenied => "Permission denied.",
+            Message::DirectoryChanged => "Directory changed.",
+            Message::PathNotFound => "Path does not exist.",
+            Message::ChangeDirectoryFailed => "Failed to change directory.",
+            Message::ClientNameRequired => "Client name is required.",
+            Message::Noted => "Noted.",
+            Message::ArgumentRequired => "Argument is required.",
+            Message::Utf8Enabled => "UTF-8 enabled.",
+            Message::Utf8Disabled => "UTF-8 disabled.",
+            Message::Utf8Usage => "Use OPTS UTF8 ON or OFF.",
+            Message::UnknownAlgorithm => "Unknown algorithm.",
+            Message::UnknownOption => "Unknown option",
+            Message::CantOpenDataConnection => "Cant open data connection.",
+            Message::LocalError => "Requested action aborted: local error.",
+            Message::TransferComplete => "Transfer complete.",
+            Message::SecurityNotCompleted => "Security data exchange not completed.",
+            Message::SessionReinitialized => "Session reinitialized.",
+            Message::NoTransferToAbort => "No transfer to abort.",
+            Message::Bye => "Bye!",
+            Message::InvalidUmask => "Invalid UMASK, octal value is expected.",
+            Message::UnknownSiteCommand => "Unknown SITE command.",
+            Message::NotImplemented => "Command not implemented.",
+            Message::UnknownCommand => "Syntax error, command unrecognized.",
+            Message::TooManyUnknownCommands => "Too many unrecognized commands.",
+            Message::SystemType => "UNIX Type: L8",
+            Message::TypeNotSupported => "Type not supported.",
+            Message::UnsupportedMode => "Unsupported transfer mode.",
+            Message::UnknownMode => "Unknown transfer mode.",
+            Message::UnsupportedStructure => "Unsupported file structure.",
+            Message::UnknownStructure => "Unknown file structure.",
+            Message::NotAFile => "Not a file.",
+            Message::ModificationTimeUnavailable => "Modification time unavailable.",
+            Message::TimestampAndPathRequired => "Timestamp and path are required.",
+            Message::InvalidTimestamp => "Invalid timestamp.",
+            Message::UseEprt => "Use EPRT on IPv6 connections.",
+            Message::AddressRequired => "Address is required",
+            Message::SyntaxError => "Syntax error in arguments",
+            Message::InvalidPort => "Invalid port",
+            Message::IllegalPortAddress => "Illegal PORT address.",
+            Message::PortSuccess => "PORT command success.",
+            Message::UseEpsv => "Use EPSV on IPv6 connections.",
+            Message::ProtocolNotSupported => "Network protocol not supported, use (1,2)",
+            Message::EprtSuccess => "EPRT command success.",
+            Message::EpsvAllSuccess => "EPSV ALL command success.",
+            Message::RestartAccepted => "Restarting at sepcific bytes.",
+            Message::InvalidRestartPosition => "Invalid restart position.",
+            Message::DownloadComplete => "Done.",
+            Message::FileNameNotAllowed => "File name not allowed.",
+            Message::FileExists => "File already exists.",
+            Message::SizeRequired => "Size is required.",
+            Message::AllocationAccepted => "Allocation accepted.",
+            Message::FileUnavailable => "File unavailable.",
+            Message::ListFailed => "Failed to list directory.",
+            Message::SetModificationTimeFailed => "Failed to set modification time.",
+            Message::LoginRequired => "Login is required.",
+            Message::ActiveModeDisabled => "Active mode disabled; use PASV/EPSV.",
+            Message::PassiveModeDisabled => "Passive mode disabled; use PORT/EPRT.",
+            Message::DataChannelRequired => "Use PASV or PORT first.",
+            Message::NoReadPermission => "No permission to read.",
+            Message::ServerReadOnly => "Server is in read-only mode.",
+            Message::NoWritePermission => "No permission to write.",
+            Message::RootUnavailable => "Server directory unavailable.",
+            Message::TransferAborted => "Connection closed; transfer aborted.",
+            Message::InvalidByteRange => "Invalid byte range.",
+            Message::StorageExceeded => "Exceeded storage allocation.",
+            Message::FileTooLarge => "File exceeds maximum allowed size.",
+            Message::OpeningListing => "Listing of directory",
+            Message::OpeningDownload => "Ready to transfer...",
+            Message::RestartingDownload => "Restarting at {offset}. Ready to transfer...",
+            Message::OpeningUpload => "Ready to receive.",
+            Message::LineTooLong => "Line too long.",
+            Message::AbortSuccessful => "ABOR command successful.",
+            Message::BadSequence => "Bad sequence of commands.",
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Server wide counters. They are always kept, as it's just a few atomics,
+/// but exported over HTTP only with the `metrics` feature.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    active_sessions: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    logins: AtomicU64,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            active_sessions: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            logins: AtomicU64::new(0),
+        }
+    }
+
+    pub fn session_started(&self) {
+        self.active_sessions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn session_ended(&self) {
+        self.active_sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn login(&self) {
+        self.logins.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, bytes: u64) {
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn received(&self, bytes: u64) {
+        self.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Formats all metrics in Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let metrics = [
+            (
+                "dock_active_sessions",
+                "gauge",
+                "Number of currently connected sessions.",
+                &self.active_sessions,
+            ),
+            (
+                "dock_bytes_sent_total",
+                "counter",
+                "Bytes of file contents sent to clients.",
+                &self.bytes_sent,
+            ),
+            (
+                "dock_bytes_received_total",
+                "counter",
+                "Bytes of file contents received from clients.",
+                &self.bytes_received,
+            ),
+            (
+                "dock_logins_total",
+                "counter",
+                "Number of successful logins.",
+                &self.logins,
+            ),
+        ];
+
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            let _ = writeln!(out, "{name} {}", value.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+/// Answers Prometheus scrapes of `/metrics` on the given listener.
+#[cfg(feature = "metrics")]
+pub async fn serve(listener: tokio::net::TcpListener) {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time,
+    };
+
+    /// Scrapers that connect and send nothing are dropped after this long.
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+    loop {
+        let Ok((mut socket, _)) = listener.accept().await else {
+            continue;
+        };
+
+        tokio::spawn(async move {
+            // Only the request line matters, headers and body are ignored.
+            let mut buf = [0u8; 1024];
+            let Ok(Ok(n)) = time::timeout(REQUEST_TIMEOUT, socket.read(&mut buf)).await else {
+                return;
+            };
+            let request = String::from_utf8_lossy(&buf[..n]);
+            let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+
+            let (status, body) = match (request_line.next(), request_line.next()) {
+                (Some("GET"), Some("/metrics")) => ("200 OK", METRICS.render()),
+                _ => ("404 Not Found", String::from("Not found.\n")),
+            };
+            let response = format!(
+                "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            let _ = socket.write_all(response.as_bytes()).await;
+            let _ = socket.shutdown().await;
+        });
+    }
+}
@@ -0,0 +1,57 @@
+use std::{path::Path, time::Duration};
+
+use async_trait::async_trait;
+
+/// Receives notifications about session events, e.g. for audit logging or webhooks.
+/// Every method does nothing by default, so implementors only override what they need.
+#[async_trait]
+pub trait SessionObserver: Send + Sync {
+    /// Called after user has logged in.
+    async fn on_login(&self, _session_id: &str, _username: &str) {}
+
+    /// Called after a file has been fully uploaded.
+    async fn on_upload_complete(
+        &self,
+        _session_id: &str,
+        _username: &str,
+        _path: &Path,
+        _bytes: u64,
+    ) {
+    }
+
+    /// Called after a file has been fully downloaded.
+    async fn on_download_complete(
+        &self,
+        _session_id: &str,
+        _username: &str,
+        _path: &Path,
+        _bytes: u64,
+    ) {
+    }
+
+    /// How often `on_transfer_progress` is called during a transfer. `None`, the default,
+    /// turns progress events off, so transfers don't track them at all.
+    fn progress_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Called periodically while a file is downloaded or uploaded. `total` is the number of
+    /// bytes expected, known only for downloads.
+    async fn on_transfer_progress(
+        &self,
+        _session_id: &str,
+        _path: &Path,
+        _bytes: u64,
+        _total: Option<u64>,
+    ) {
+    }
+
+    /// Called when session ends for any reason.
+    async fn on_disconnect(&self, _session_id: &str) {}
+}
+
+/// Observer that ignores every event.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopObserver;
+
+impl SessionObserver for NoopObserver {}
@@ -0,0 +1,117 @@
+/// Checks if a name contains wildcard characters, so it should be matched rather than looked up.
+pub fn is_pattern(name: &str) -> bool {
+    name.contains(['*', '?', '['])
+}
+
+/// Matches a file name against a shell style pattern. Supports `*`, `?` and character
+/// classes like `[abc]`, `[a-z]` or `[!abc]`. An unclosed `[` matches itself.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    // Where to resume after the last `*` if the rest doesn't match.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, n));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match match_class(&pattern[p..], name[n]) {
+                Some((true, len)) => Some(len),
+                Some((false, _)) => None,
+                None => (name[n] == '[').then_some(1),
+            },
+            Some(c) => (*c == name[n]).then_some(1),
+            None => None,
+        };
+
+        match (step, backtrack) {
+            (Some(len), _) => {
+                p += len;
+                n += 1;
+            }
+            (None, Some((star_p, star_n))) => {
+                // Let the last `*` swallow one more character and try again.
+                p = star_p;
+                n = star_n + 1;
+                backtrack = Some((star_p, star_n + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Matches a character against a class at the start of `pattern`.
+/// Returns whether it matched and the length of the class, or `None` if the class isn't closed.
+fn match_class(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negated = matches!(pattern.get(i), Some('!' | '^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let start = *pattern.get(i)?;
+        // `]` right after the opening bracket is a literal.
+        if start == ']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+
+        if pattern.get(i + 1) == Some(&'-')
+            && let Some(&end) = pattern.get(i + 2)
+            && end != ']'
+        {
+            matched |= (start..=end).contains(&c);
+            i += 3;
+        } else {
+            matched |= start == c;
+            i += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn star_matches_any_run() {
+        assert!(matches("*.txt", "notes.txt"));
+        assert!(matches("*.txt", ".txt"));
+        assert!(matches("a*b*c", "aXXbYYc"));
+        assert!(!matches("*.txt", "notes.txt.bak"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character() {
+        assert!(matches("file?.log", "file1.log"));
+        assert!(!matches("file?.log", "file.log"));
+        assert!(!matches("file?.log", "file12.log"));
+    }
+
+    #[test]
+    fn character_classes() {
+        assert!(matches("[abc].txt", "b.txt"));
+        assert!(!matches("[abc].txt", "d.txt"));
+        assert!(matches("[a-c]1", "c1"));
+        assert!(matches("[!a-c]1", "d1"));
+        assert!(!matches("[!a-c]1", "a1"));
+        assert!(matches("[]]", "]"));
+    }
+
+    #[test]
+    fn unclosed_class_matches_itself() {
+        assert!(matches("[abc", "[abc"));
+        assert!(!matches("[abc", "a"));
+    }
+}
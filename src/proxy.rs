@@ -0,0 +1,108 @@
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::{io::AsyncReadExt, net::TcpStream, time};
+
+/// Longest possible PROXY protocol v1 header, including `CRLF`.
+const MAX_HEADER_LEN: usize = 107;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reads a PROXY protocol v1 header sent by a load balancer and returns the original client address.
+/// For `UNKNOWN` connections the address of the balancer itself is returned.
+pub async fn read_header(stream: &mut TcpStream, peer: SocketAddr) -> io::Result<SocketAddr> {
+    let header = time::timeout(HEADER_TIMEOUT, read_line(stream))
+        .await
+        .map_err(|_| io::Error::new(ErrorKind::TimedOut, "no PROXY header received"))??;
+    parse_header(&header, peer)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "malformed PROXY header"))
+}
+
+/// Reads bytes up to `CRLF` one at a time, so nothing after the header is consumed.
+async fn read_line(stream: &mut TcpStream) -> io::Result<String> {
+    let mut line = Vec::with_capacity(MAX_HEADER_LEN);
+    while !line.ends_with(b"\r\n") {
+        if line.len() == MAX_HEADER_LEN {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "PROXY header is too long",
+            ));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    line.truncate(line.len() - 2);
+    String::from_utf8(line)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "malformed PROXY header"))
+}
+
+/// Parses a header line like `PROXY TCP4 192.0.2.1 198.51.100.1 56324 21`.
+fn parse_header(header: &str, peer: SocketAddr) -> Option<SocketAddr> {
+    let mut parts = header.split(' ');
+    if parts.next()? != "PROXY" {
+        return None;
+    }
+
+    let protocol = parts.next()?;
+    if protocol == "UNKNOWN" {
+        return Some(peer);
+    }
+
+    let source: IpAddr = parts.next()?.parse().ok()?;
+    let destination: IpAddr = parts.next()?.parse().ok()?;
+    let source_port: u16 = parts.next()?.parse().ok()?;
+    let _destination_port: u16 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+
+    let matches_protocol = match protocol {
+        "TCP4" => source.is_ipv4() && destination.is_ipv4(),
+        "TCP6" => source.is_ipv6() && destination.is_ipv6(),
+        _ => false,
+    };
+    matches_protocol.then_some(SocketAddr::new(source, source_port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balancer() -> SocketAddr {
+        "10.0.0.1:40000".parse().unwrap()
+    }
+
+    #[test]
+    fn parses_tcp4_header() {
+        let addr = parse_header("PROXY TCP4 192.0.2.1 198.51.100.1 56324 21", balancer());
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn parses_tcp6_header() {
+        let addr = parse_header("PROXY TCP6 2001:db8::1 2001:db8::2 4000 21", balancer());
+        assert_eq!(addr, Some("[2001:db8::1]:4000".parse().unwrap()));
+    }
+
+    #[test]
+    fn unknown_protocol_keeps_peer() {
+        assert_eq!(parse_header("PROXY UNKNOWN", balancer()), Some(balancer()));
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        for header in [
+            "",
+            "GET / HTTP/1.1",
+            "PROXY TCP4 192.0.2.1 198.51.100.1 56324",
+            "PROXY TCP4 192.0.2.1 198.51.100.1 56324 21 extra",
+            "PROXY TCP4 2001:db8::1 198.51.100.1 56324 21",
+            "PROXY TCP6 192.0.2.1 198.51.100.1 56324 21",
+            "PROXY TCP4 192.0.2.1 198.51.100.1 70000 21",
+            "PROXY UDP4 192.0.2.1 198.51.100.1 56324 21",
+        ] {
+            assert_eq!(parse_header(header, balancer()), None, "{header}");
+        }
+    }
+}
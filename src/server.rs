@@ -1,17 +1,40 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::{Result, anyhow};
-use tokio::net::TcpListener;
-use tracing::{error, info};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    signal,
+    sync::broadcast,
+    task::JoinSet,
+    time,
+};
+use tracing::{Instrument, error, field, info, info_span, warn};
 use tracing_subscriber::{EnvFilter, fmt};
 
 use crate::{
+    auth::Authenticator,
     config::Config,
-    session::{ConnectionError, Session},
+    hostname::HostnameResolver,
+    masquerade::MasqueradeResolver,
+    metrics::METRICS,
+    observer::{NoopObserver, SessionObserver},
+    proxy,
+    session::{self, ConnectionError, Session},
+    usage::DiskUsage,
+    vfs::{DiskFs, VirtualFs},
 };
 
 pub struct Server {
     config: Config,
+    authenticator: Arc<dyn Authenticator>,
+    vfs: Arc<dyn VirtualFs>,
+    observer: Arc<dyn SessionObserver>,
 }
 
 fn init_logging() {
@@ -24,9 +47,222 @@ fn init_logging() {
         .init();
 }
 
+/// Counts recent connections per source address.
+struct ConnectionLimiter {
+    max_per_minute: u32,
+    recent: HashMap<IpAddr, VecDeque<Instant>>,
+    last_sweep: Instant,
+}
+
+impl ConnectionLimiter {
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    fn new(max_per_minute: u32) -> Self {
+        Self {
+            max_per_minute,
+            recent: HashMap::new(),
+            last_sweep: Instant::now(),
+        }
+    }
+
+    /// Records a connection attempt and checks if it fits the limit.
+    fn allow(&mut self, ip: IpAddr) -> bool {
+        if self.max_per_minute == 0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        if now.duration_since(self.last_sweep) >= Self::WINDOW {
+            // Forget addresses that haven't connected within the window.
+            self.recent.retain(|_, times| {
+                times
+                    .back()
+                    .is_some_and(|t| now.duration_since(*t) < Self::WINDOW)
+            });
+            self.last_sweep = now;
+        }
+
+        let times = self.recent.entry(ip).or_default();
+        while times
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= Self::WINDOW)
+        {
+            times.pop_front();
+        }
+
+        if times.len() >= self.max_per_minute as usize {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+/// Tells client why it's not served before dropping the connection.
+async fn refuse(mut socket: TcpStream, message: &str) {
+    let _ = socket
+        .write_all(format!("421 {message}\r\n").as_bytes())
+        .await;
+}
+
+/// Answers each probe with a status line and closes it. Probes that only check whether
+/// the connection is accepted work as well.
+async fn serve_healthcheck(listener: TcpListener) {
+    loop {
+        if let Ok((mut socket, _)) = listener.accept().await {
+            // A probe that doesn't read must not hold up the next one.
+            tokio::spawn(async move {
+                let _ =
+                    time::timeout(Duration::from_secs(5), socket.write_all(b"200 OK\r\n")).await;
+            });
+        }
+    }
+}
+
+/// Keeps urgent data in the normal stream of the control connection. Clients send `ABOR`
+/// or the Telnet sequence before it as urgent data, which would otherwise be dropped.
+#[cfg(unix)]
+pub(crate) fn set_oob_inline(socket: &TcpStream) {
+    use std::os::fd::AsRawFd;
+
+    let enable: libc::c_int = 1;
+    // SAFETY: the descriptor is open and the option value outlives the call.
+    unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_OOBINLINE,
+            (&enable as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        );
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_oob_inline(_socket: &TcpStream) {}
+
+/// Resolves when the process receives SIGINT (Ctrl-C) or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut s) => {
+                s.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Makes `root` the root directory of the process. Needs root privileges.
+#[cfg(unix)]
+fn enter_chroot(root: &str) -> Result<()> {
+    std::os::unix::fs::chroot(root).map_err(|e| anyhow!("failed to chroot into '{root}': {e}"))?;
+    std::env::set_current_dir("/")
+        .map_err(|e| anyhow!("failed to change directory after chroot: {e}"))
+}
+
+#[cfg(not(unix))]
+fn enter_chroot(_root: &str) -> Result<()> {
+    Err(anyhow!("chroot is only supported on Unix"))
+}
+
+/// Resolves `run_as_user` and `run_as_group` to IDs. Returns `None` when neither is set.
+#[cfg(unix)]
+fn lookup_run_as(user: Option<&str>, group: Option<&str>) -> Result<Option<(Option<u32>, u32)>> {
+    let user = user
+        .map(|name| {
+            uzers::get_user_by_name(name)
+                .ok_or_else(|| anyhow!("user '{name}' to run as does not exist"))
+        })
+        .transpose()?;
+    let gid = match group {
+        Some(name) => uzers::get_group_by_name(name)
+            .ok_or_else(|| anyhow!("group '{name}' to run as does not exist"))?
+            .gid(),
+        None => match &user {
+            Some(user) => user.primary_group_id(),
+            None => return Ok(None),
+        },
+    };
+    Ok(Some((user.map(|u| u.uid()), gid)))
+}
+
+#[cfg(not(unix))]
+fn lookup_run_as(_user: Option<&str>, _group: Option<&str>) -> Result<Option<(Option<u32>, u32)>> {
+    Ok(None)
+}
+
+/// Switches the process to the given group and, if set, user. Group goes first,
+/// as changing it is no longer allowed once the user isn't root.
+#[cfg(unix)]
+fn drop_privileges(uid: Option<u32>, gid: u32) -> Result<()> {
+    let last_error = std::io::Error::last_os_error;
+    // Supplementary groups of the starting user would be kept otherwise.
+    if unsafe { libc::setgroups(1, &gid) } != 0 {
+        return Err(anyhow!(
+            "failed to drop supplementary groups: {}",
+            last_error()
+        ));
+    }
+    if unsafe { libc::setgid(gid) } != 0 {
+        return Err(anyhow!("failed to switch to group {gid}: {}", last_error()));
+    }
+    if let Some(uid) = uid
+        && unsafe { libc::setuid(uid) } != 0
+    {
+        return Err(anyhow!("failed to switch to user {uid}: {}", last_error()));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn drop_privileges(_uid: Option<u32>, _gid: u32) -> Result<()> {
+    Err(anyhow!("dropping privileges is only supported on Unix"))
+}
+
 impl Server {
     pub fn new(config: Config) -> Self {
-        Server { config }
+        let authenticator = Arc::new(config.clone());
+        // After chroot the configured root is the new `/`, which `serve` enters before any file is touched.
+        let root = if config.chroot { "/" } else { &config.root };
+        let vfs = Arc::new(DiskFs::new(root).follow_symlinks(config.follow_symlinks));
+        Server {
+            config,
+            authenticator,
+            vfs,
+            observer: Arc::new(NoopObserver),
+        }
+    }
+
+    /// Replaces the default authenticator, which uses users from configuration.
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.authenticator = authenticator;
+        self
+    }
+
+    /// Replaces the default filesystem, which serves files from the configured root.
+    pub fn with_filesystem(mut self, vfs: Arc<dyn VirtualFs>) -> Self {
+        self.vfs = vfs;
+        self
+    }
+
+    /// Sets an observer that gets notified about session events.
+    pub fn with_observer(mut self, observer: Arc<dyn SessionObserver>) -> Self {
+        self.observer = observer;
+        self
     }
 
     pub async fn start_server(&self) -> Result<()> {
@@ -35,37 +271,336 @@ impl Server {
         let listener = TcpListener::bind(&self.config.address)
             .await
             .map_err(|_| anyhow!("failed to bind to given address"))?;
-        info!("Listening on {}", self.config.address);
+        self.serve(listener).await
+    }
+
+    /// Accepts clients on an already bound listener until the process is asked to stop.
+    /// Unlike `start_server`, doesn't set up logging, so embedders can use their own.
+    pub async fn serve(&self, listener: TcpListener) -> Result<()> {
+        let address = listener.local_addr()?;
+        info!("Listening on {address}");
+
+        if let Some(address) = &self.config.healthcheck_address {
+            let healthcheck_listener = TcpListener::bind(address)
+                .await
+                .map_err(|_| anyhow!("failed to bind health check listener to given address"))?;
+            tokio::spawn(serve_healthcheck(healthcheck_listener));
+            info!("Serving health checks on {address}");
+        }
+
+        if let Some(address) = &self.config.metrics_address {
+            #[cfg(feature = "metrics")]
+            {
+                let metrics_listener = TcpListener::bind(address)
+                    .await
+                    .map_err(|_| anyhow!("failed to bind metrics listener to given address"))?;
+                tokio::spawn(crate::metrics::serve(metrics_listener));
+                info!("Serving metrics on {address}");
+            }
+
+            #[cfg(not(feature = "metrics"))]
+            warn!(address=%address, "Metrics address is set, but server was built without the `metrics` feature.");
+        }
+
+        // Users and groups are looked up before chroot, as their database is outside of it.
+        let run_as = lookup_run_as(
+            self.config.run_as_user.as_deref(),
+            self.config.run_as_group.as_deref(),
+        )?;
+        let masquerade = self
+            .config
+            .masquerade_host
+            .as_ref()
+            .map(|host| Arc::new(MasqueradeResolver::new(host)));
+        if self.config.chroot {
+            // Time zone and the masquerade address come from files outside of the new root,
+            // so they're loaded beforehand. The resolver keeps using this address if later lookups fail.
+            session::local_utc_offset(0);
+            if let Some(masquerade) = &masquerade {
+                masquerade.resolve().await;
+            }
+            enter_chroot(&self.config.root)?;
+            info!(root=%self.config.root, "Entered chroot.");
+        }
+        if let Some((uid, gid)) = run_as {
+            drop_privileges(uid, gid)?;
+            let uid = uid.map_or_else(|| String::from("unchanged"), |uid| uid.to_string());
+            info!(uid=%uid, gid=%gid, "Dropped privileges.");
+        } else if self.config.chroot {
+            warn!("Server is chrooted, but still runs with privileges it was started with.");
+        }
 
         let arc_config = Arc::new(self.config.clone());
+        let (shutdown_tx, _) = broadcast::channel::<()>(1);
+        let mut sessions = JoinSet::new();
+        let mut handshakes = JoinSet::new();
+        let mut limiter = ConnectionLimiter::new(self.config.max_connections_per_minute);
+        let hostnames = self
+            .config
+            .resolve_client_hostname
+            .then(|| Arc::new(HostnameResolver::new()));
+        let disk_usage = Arc::new(DiskUsage::new());
+
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
 
         loop {
-            let (socket, addr) = listener
-                .accept()
-                .await
-                .map_err(|_| anyhow!("cannot accept connection"))?;
+            let (socket, addr) = tokio::select! {
+                accepted = listener.accept() => {
+                    let (mut socket, addr) = accepted.map_err(|_| anyhow!("cannot accept connection"))?;
+                    if !self.config.proxy_protocol {
+                        (socket, addr)
+                    } else {
+                        // Headers are read in background, so a slow balancer doesn't hold up accepting.
+                        handshakes.spawn(async move {
+                            let result = proxy::read_header(&mut socket, addr).await;
+                            (socket, addr, result)
+                        });
+                        continue;
+                    }
+                }
+                Some(handshake) = handshakes.join_next(), if !handshakes.is_empty() => {
+                    let Ok((socket, peer, result)) = handshake else {
+                        continue;
+                    };
+                    match result {
+                        Ok(addr) => (socket, addr),
+                        Err(e) => {
+                            warn!(ip=%peer, reason=%e, "Connection refused, PROXY header is missing or invalid.");
+                            continue;
+                        }
+                    }
+                }
+                Some(_) = sessions.join_next(), if !sessions.is_empty() => continue,
+                _ = &mut shutdown => break,
+            };
 
             info!(ip=%addr, "Got new connection.");
+
+            if !self.config.is_ip_allowed(addr.ip()) {
+                warn!(ip=%addr, "Connection refused by IP rules.");
+                refuse(socket, "Connection refused.").await;
+                continue;
+            }
+
+            if !limiter.allow(addr.ip().to_canonical()) {
+                warn!(ip=%addr, "Connection refused by rate limit.");
+                refuse(socket, "Too many connections from your address.").await;
+                continue;
+            }
+
+            set_oob_inline(&socket);
+
             let arc_config_cloned = Arc::clone(&arc_config);
+            let authenticator = Arc::clone(&self.authenticator);
+            let vfs = Arc::clone(&self.vfs);
+            let observer = Arc::clone(&self.observer);
+            let masquerade = masquerade.clone();
+            let hostnames = hostnames.clone();
+            let shutdown_rx = shutdown_tx.subscribe();
+            let proxy_protocol = self.config.proxy_protocol;
+            let disk_usage = Arc::clone(&disk_usage);
 
-            tokio::spawn(async move {
+            sessions.spawn(async move {
+                METRICS.session_started();
                 let session_id = cuid2::cuid();
-                let mut session = Session::new(&session_id, socket, (*arc_config_cloned).clone());
+                let mut session = Session::new(
+                    &session_id,
+                    socket,
+                    (*arc_config_cloned).clone(),
+                    authenticator,
+                    vfs,
+                    Arc::clone(&observer),
+                )
+                .with_disk_usage(disk_usage);
+                if let Some(resolver) = masquerade {
+                    session = session.with_masquerade(resolver);
+                }
+                if proxy_protocol {
+                    session = session.with_client_addr(addr);
+                }
                 info!(session_id=%session_id, ip=%addr, "Initiated new session.");
-                if let Err(e) = session.run_session().await {
-                    match e {
+                // Host name is a field of the session span, so every line logged after the lookup carries it.
+                let span = info_span!("session", hostname = field::Empty);
+                if let Some(resolver) = hostnames {
+                    // Lookup runs alongside the session, so a slow resolver doesn't delay the greeting.
+                    let span = span.clone();
+                    tokio::spawn(async move {
+                        if let Some(hostname) = resolver.resolve(addr.ip()).await {
+                            span.record("hostname", field::display(&hostname));
+                        }
+                    });
+                }
+                let result = session.run_session(shutdown_rx).instrument(span.clone()).await;
+                if let Err(e) = result {
+                    span.in_scope(|| match e {
                         ConnectionError::ClosedByQuit => {
                             info!(session_id=%session_id, "Session was closed by user.");
                         }
                         ConnectionError::Disconnected => {
                             info!(session_id=%session_id, "Session was closed because user had disconnected.");
                         }
+                        ConnectionError::ServerShutdown => {
+                            info!(session_id=%session_id, "Session was closed because server is shutting down.");
+                        }
+                        ConnectionError::TooManyFailedLogins => {
+                            warn!(session_id=%session_id, ip=%addr, "Session was closed after too many failed logins.");
+                        }
+                        ConnectionError::TooManyUnrecognizedCommands => {
+                            warn!(session_id=%session_id, ip=%addr, "Session was closed after too many unrecognized commands.");
+                        }
                         _ => {
                             error!(session_id=%session_id, reason=%e, "Session failed.");
                         }
-                    }
+                    });
                 }
+                METRICS.session_ended();
+                observer.on_disconnect(&session_id).await;
             });
         }
+
+        drop(listener);
+        info!(
+            sessions = sessions.len(),
+            "Shutting down, waiting for active sessions."
+        );
+        let _ = shutdown_tx.send(());
+
+        let grace = Duration::from_secs(self.config.shutdown_timeout);
+        let drain = async { while sessions.join_next().await.is_some() {} };
+        if time::timeout(grace, drain).await.is_err() {
+            warn!(
+                sessions = sessions.len(),
+                "Grace period expired, aborting remaining sessions."
+            );
+            sessions.shutdown().await;
+        }
+
+        info!("Server stopped.");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::{io::AsyncReadExt, net::TcpSocket};
+
+    use super::*;
+    use crate::config::{Permissions, User};
+
+    /// Connects to the server from `source` and returns the first line it sends.
+    async fn first_line(server: SocketAddr, source: IpAddr) -> String {
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind(SocketAddr::new(source, 0)).unwrap();
+        let mut stream = socket.connect(server).await.unwrap();
+        let mut line = Vec::new();
+        while !line.ends_with(b"\r\n") {
+            let mut byte = [0u8];
+            let n = time::timeout(Duration::from_secs(5), stream.read(&mut byte))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(n > 0, "connection closed, got {line:?}");
+            line.push(byte[0]);
+        }
+        String::from_utf8(line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn health_check_answers_with_status_line() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve_healthcheck(listener));
+
+        for _ in 0..2 {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            let mut reply = String::new();
+            time::timeout(Duration::from_secs(5), stream.read_to_string(&mut reply))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(reply, "200 OK\r\n");
+        }
+        server.abort();
+    }
+
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn hostname_is_logged_with_session_lines() {
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        // Sessions run on this test's thread, so a thread local subscriber sees them.
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let localhost = IpAddr::from([127, 0, 0, 1]);
+        let hostname = HostnameResolver::new().resolve(localhost).await.unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let config = Config::builder("127.0.0.1:0", root.path().to_string_lossy())
+            .user(User::new("alice", "secret", Permissions::All))
+            .configure(|c| c.resolve_client_hostname = true)
+            .build()
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move { Server::new(config).serve(listener).await });
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let field = format!("hostname={hostname}");
+        // The lookup runs alongside the session, so commands sent before it ends don't carry the name yet.
+        for _ in 0..50 {
+            stream.write_all(b"SYST\r\n").await.unwrap();
+            time::sleep(Duration::from_millis(20)).await;
+            let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+            if logs
+                .lines()
+                .any(|l| l.contains("Received command.") && l.contains(&field))
+            {
+                server.abort();
+                return;
+            }
+        }
+        panic!("no command was logged with {field}");
+    }
+
+    #[tokio::test]
+    async fn ip_rules_refuse_denied_networks() {
+        let root = tempfile::tempdir().unwrap();
+        let config = Config::builder("127.0.0.1:0", root.path().to_string_lossy())
+            .user(User::new("alice", "secret", Permissions::All))
+            .configure(|c| {
+                c.allow_ips = vec!["127.0.0.0/8".parse().unwrap()];
+                c.deny_ips = vec!["127.0.0.2/32".parse().unwrap()];
+            })
+            .build()
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move { Server::new(config).serve(listener).await });
+
+        let accepted = first_line(address, "127.0.0.1".parse().unwrap()).await;
+        assert!(accepted.starts_with("220"), "{accepted}");
+        let refused = first_line(address, "127.0.0.2".parse().unwrap()).await;
+        assert_eq!(refused, "421 Connection refused.\r\n");
+        server.abort();
     }
 }
@@ -1,27 +1,99 @@
 use std::{
-    fs::Permissions,
-    net::{Ipv4Addr, SocketAddr},
-    path::{Path, PathBuf},
-    time::Duration,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-#[cfg(unix)]
-use std::os::unix::fs::PermissionsExt;
-
-use anyhow::{Result, anyhow, bail};
+use anyhow::Result;
 use thiserror::Error;
 use tokio::{
-    fs::{self, File},
-    io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, DuplexStream},
     net::{TcpListener, TcpStream},
+    sync::broadcast,
     time,
 };
-use tracing::info;
-
-use crate::{commands::Commands, config::Config};
+use tracing::{debug, info, warn};
+
+use crate::{
+    auth::{AuthResult, Authenticator},
+    checksum::{self, HASH_ALGORITHMS, HashAlgorithm},
+    commands::{
+        COMMAND_TABLE, Commands, SITE_COMMAND_TABLE, command_help, redact_argument,
+        site_command_help,
+    },
+    config::{Config, ListingTimezone},
+    masquerade::MasqueradeResolver,
+    messages::Message,
+    metrics::METRICS,
+    observer::SessionObserver,
+    pattern,
+    transfer::{self, FileStructure, Progress, TransferMode, TransferType},
+    usage::DiskUsage,
+    vfs::{self, FileInfo, VirtualFs},
+};
 
-const SERVER_FEATURES: [&str; 4] = ["UTF8", "MLST type*;size*;modify*;perm*;", "PASV", "PORT"];
+/// `FEAT` lines and the command each of them depends on.
+const SERVER_FEATURES: &[(&str, Commands)] = &[
+    ("UTF8", Commands::Option),
+    ("MLST", Commands::MachineListEntry),
+    ("PASV", Commands::Passive),
+    ("PORT", Commands::Port),
+    ("EPSV", Commands::ExtendedPassive),
+    ("EPRT", Commands::ExtendedPort),
+    ("SIZE", Commands::Size),
+    ("MDTM", Commands::ModificationTime),
+    ("MFMT", Commands::SetModifyTime),
+    ("HASH", Commands::Hash),
+    ("REST STREAM", Commands::Rest),
+    ("TVFS", Commands::ChangeDir),
+];
+/// Facts that `MLST` and `MLSD` can report.
+#[cfg(unix)]
+const MLST_FACTS: &[&str] = &[
+    "type",
+    "size",
+    "modify",
+    "perm",
+    "UNIX.mode",
+    "UNIX.owner",
+    "UNIX.group",
+    "UNIX.uid",
+    "UNIX.gid",
+];
+#[cfg(not(unix))]
+const MLST_FACTS: &[&str] = &["type", "size", "modify", "perm"];
+const LOGIN_FAILURE_DELAY: Duration = Duration::from_secs(1);
+/// Unrecognized commands in a row after which the client is disconnected.
+const MAX_UNRECOGNIZED_COMMANDS: u32 = 10;
 const DISALLOWED_FILENAMES: [&str; 2] = ["..", "."];
+/// Commands that move data over a data connection. Only one of them may run at a time.
+const TRANSFER_COMMANDS: &[Commands] = &[
+    Commands::Retrive,
+    Commands::Store,
+    Commands::StoreUnique,
+    Commands::List,
+    Commands::MachineListDir,
+];
+/// Commands whose argument is a path.
+const PATH_COMMANDS: &[Commands] = &[
+    Commands::ChangeDir,
+    Commands::List,
+    Commands::MachineListDir,
+    Commands::MachineListEntry,
+    Commands::Size,
+    Commands::ModificationTime,
+    Commands::SetModifyTime,
+    Commands::Hash,
+    Commands::CrcChecksum,
+    Commands::Md5Checksum,
+    Commands::Retrive,
+    Commands::Store,
+    Commands::StoreUnique,
+    Commands::Available,
+    Commands::Status,
+];
 
 macro_rules! reply {
     ($self:expr, $code:expr, $message:expr) => {
@@ -36,10 +108,66 @@ macro_rules! reply_ok {
     };
 }
 
+macro_rules! reply_fs_error {
+    ($self:expr, $error:expr, $code:expr, $message:expr) => {
+        $self.reply_fs_error(&$error, $code, $message).await?;
+        return Ok(());
+    };
+}
+
 macro_rules! require_authorization {
     ($self:expr) => {
         if !$self.authorized {
-            $self.reply(530, "Login is required.").await?;
+            $self.reply(530, Message::LoginRequired).await?;
+            return Ok(());
+        }
+    };
+}
+
+macro_rules! require_active_mode {
+    ($self:expr) => {
+        if $self.config.passive_only {
+            $self.reply(500, Message::ActiveModeDisabled).await?;
+            return Ok(());
+        }
+    };
+}
+
+macro_rules! require_passive_mode {
+    ($self:expr) => {
+        if $self.config.active_only {
+            $self.reply(500, Message::PassiveModeDisabled).await?;
+            return Ok(());
+        }
+    };
+}
+
+macro_rules! require_data_channel {
+    ($self:expr) => {
+        if $self.active_addr.is_none() && $self.passive_listener.is_none() {
+            $self.reply(425, Message::DataChannelRequired).await?;
+            return Ok(());
+        }
+    };
+}
+
+macro_rules! require_read_access {
+    ($self:expr) => {
+        if !$self.can_read() {
+            $self.reply(550, Message::NoReadPermission).await?;
+            return Ok(());
+        }
+    };
+}
+
+macro_rules! require_write_access {
+    ($self:expr) => {
+        if $self.config.read_only {
+            $self.reply(550, Message::ServerReadOnly).await?;
+            return Ok(());
+        }
+        if !$self.can_write() {
+            $self.reply(550, Message::NoWritePermission).await?;
             return Ok(());
         }
     };
@@ -59,6 +187,15 @@ pub enum ConnectionError {
     #[error("session ended manually by client")]
     ClosedByQuit,
 
+    #[error("server is shutting down")]
+    ServerShutdown,
+
+    #[error("too many failed login attempts")]
+    TooManyFailedLogins,
+
+    #[error("too many unrecognized commands")]
+    TooManyUnrecognizedCommands,
+
     #[error("data connection failed: {0}")]
     DataConnectionFailed(String),
 
@@ -66,51 +203,228 @@ pub enum ConnectionError {
     FileSystemError,
 }
 
-#[derive(Debug)]
-pub struct Session {
+/// Text of a reply: either a fixed `Message`, which configuration may replace, or one built on the spot.
+trait ReplyText: Send {
+    fn resolve(self, config: &Config) -> String;
+}
+
+impl ReplyText for &str {
+    fn resolve(self, _config: &Config) -> String {
+        self.to_string()
+    }
+}
+
+impl ReplyText for Message {
+    fn resolve(self, config: &Config) -> String {
+        config.message(self).to_string()
+    }
+}
+
+/// Control connection of a session. Besides TCP it's implemented for in-memory
+/// `DuplexStream`, so the protocol can be driven without binding ports.
+pub trait ControlStream: AsyncRead + AsyncWrite + Unpin + Send {
+    fn local_addr(&self) -> std::io::Result<SocketAddr>;
+    fn peer_addr(&self) -> std::io::Result<SocketAddr>;
+
+    /// Reads data that has already arrived, without waiting. Returns `WouldBlock` if there is none.
+    fn try_read_buffered(&self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Err(ErrorKind::WouldBlock.into())
+    }
+}
+
+impl ControlStream for TcpStream {
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    /// Asks the socket directly, as tokio treats a short read as having drained it.
+    #[cfg(unix)]
+    fn try_read_buffered(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        use std::os::fd::AsRawFd;
+
+        // SAFETY: the descriptor is open and `buf` is valid for writes of its length.
+        let n = unsafe {
+            libc::recv(
+                self.as_raw_fd(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+                libc::MSG_DONTWAIT,
+            )
+        };
+        if n < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
+}
+
+/// In-memory streams pretend to be a loopback connection.
+impl ControlStream for DuplexStream {
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+    }
+
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+    }
+}
+
+/// Session served over a regular TCP connection.
+pub type TcpSession = Session<TcpStream>;
+
+/// What stops an upload from growing, with the number of bytes it may still write.
+#[derive(Debug, Clone, Copy)]
+enum UploadLimit {
+    Quota(u64),
+    FileSize(u64),
+}
+
+impl UploadLimit {
+    fn bytes(self) -> u64 {
+        match self {
+            UploadLimit::Quota(b) | UploadLimit::FileSize(b) => b,
+        }
+    }
+
+    fn message(self) -> Message {
+        match self {
+            UploadLimit::Quota(_) => Message::StorageExceeded,
+            UploadLimit::FileSize(_) => Message::FileTooLarge,
+        }
+    }
+}
+
+pub struct Session<S: ControlStream> {
     username: String,
     authorized: bool,
+    failed_logins: u32,
+    unrecognized_commands: u32,
+    /// Client software name sent with `CLNT`.
+    client: Option<String>,
+    masquerade: Option<Arc<MasqueradeResolver>>,
+    /// Address of the client when it differs from the peer, e.g. one taken from a PROXY header.
+    client_addr: Option<SocketAddr>,
     current_dir: PathBuf,
-    connection: TcpStream,
+    connection: S,
+    /// Bytes received after the last complete command line.
+    read_buffer: Vec<u8>,
+    /// Set while the rest of a too long line is being dropped.
+    discarding_line: bool,
+    /// Set once client has closed its side of the control connection. Lines already
+    /// in the read buffer are still handled.
+    control_closed: bool,
+    /// Set once a data connection is open for a transfer, until the command that started it is handled.
+    transfer_in_progress: bool,
     rest_offset: u64,
+    transfer_type: TransferType,
+    transfer_mode: TransferMode,
+    file_structure: FileStructure,
+    utf8: bool,
+    mlst_facts: Vec<&'static str>,
+    hash_algorithm: HashAlgorithm,
+    /// Set with `SITE UMASK`, applies to files created by `STOR` and `STOU`.
+    umask: Option<u32>,
     active_addr: Option<SocketAddr>,
     passive_listener: Option<TcpListener>,
     config: Config,
+    authenticator: Arc<dyn Authenticator>,
+    vfs: Arc<dyn VirtualFs>,
+    disk_usage: Arc<DiskUsage>,
+    observer: Arc<dyn SessionObserver>,
     id: String,
 }
 
-impl Session {
-    pub fn new(id: &String, connection: TcpStream, config: Config) -> Self {
+impl<S: ControlStream> Session<S> {
+    pub fn new(
+        id: &String,
+        connection: S,
+        config: Config,
+        authenticator: Arc<dyn Authenticator>,
+        vfs: Arc<dyn VirtualFs>,
+        observer: Arc<dyn SessionObserver>,
+    ) -> Self {
         Self {
             id: id.to_owned(),
             connection,
+            read_buffer: Vec::new(),
+            discarding_line: false,
+            control_closed: false,
+            transfer_in_progress: false,
             config,
+            authenticator,
+            vfs,
+            disk_usage: Arc::new(DiskUsage::new()),
+            observer,
             rest_offset: 0,
+            transfer_type: TransferType::default(),
+            transfer_mode: TransferMode::default(),
+            file_structure: FileStructure::default(),
+            utf8: true,
+            mlst_facts: MLST_FACTS.to_vec(),
+            hash_algorithm: HashAlgorithm::default(),
+            umask: None,
             active_addr: None,
             passive_listener: None,
             current_dir: PathBuf::from("/"),
             username: String::new(),
             authorized: false,
+            failed_logins: 0,
+            unrecognized_commands: 0,
+            client: None,
+            masquerade: None,
+            client_addr: None,
         }
     }
 
+    /// Sets resolver of the address advertised in `PASV` replies.
+    pub fn with_masquerade(mut self, resolver: Arc<MasqueradeResolver>) -> Self {
+        self.masquerade = Some(resolver);
+        self
+    }
+
+    /// Shares size of the served root with other sessions, so quotas don't need it counted by each of them.
+    pub fn with_disk_usage(mut self, usage: Arc<DiskUsage>) -> Self {
+        self.disk_usage = usage;
+        self
+    }
+
+    /// Sets the real address of the client, for connections relayed by a load balancer.
+    pub fn with_client_addr(mut self, addr: SocketAddr) -> Self {
+        self.client_addr = Some(addr);
+        self
+    }
+
+    /// Returns address of the client, which is the peer unless a balancer has reported another one.
+    fn client_addr(&self) -> Option<SocketAddr> {
+        self.client_addr
+            .or_else(|| self.connection.peer_addr().ok())
+    }
+
+    /// Returns IP address of the client as text, for logs and status.
+    fn client_ip(&self) -> String {
+        self.client_addr()
+            .map(|a| a.ip().to_string())
+            .unwrap_or_else(|| String::from("unknown"))
+    }
+
     /// Formats file permissions in Unix format (e.g., drwxr-xr-x)
-    fn format_unix_permissions(is_dir: bool, permissions: &Permissions) -> String {
+    fn format_unix_permissions(entry: &FileInfo) -> String {
+        let mode = entry.mode;
         let mut perms = String::with_capacity(10);
 
         // File type
-        perms.push(if is_dir { 'd' } else { '-' });
-
-        // Get Unix permissions or use defaults for non-Unix systems
-        #[cfg(unix)]
-        let mode = permissions.mode();
-
-        #[cfg(not(unix))]
-        let mode = if permissions.readonly() {
-            0o444 // r--r--r--
+        perms.push(if entry.is_symlink {
+            'l'
+        } else if entry.is_dir {
+            'd'
         } else {
-            0o644 // rw-r--r--
-        };
+            '-'
+        });
 
         // Owner permissions
         perms.push(if mode & 0o400 != 0 { 'r' } else { '-' });
@@ -130,118 +444,370 @@ impl Session {
         perms
     }
 
+    /// Builds an `ls -l` style line for a directory entry.
+    fn format_entry(&self, entry: &FileInfo) -> String {
+        let perms = Self::format_unix_permissions(entry);
+        // Pseudo values for backends that don't know ownership.
+        let owner = entry.owner.as_deref().unwrap_or("root");
+        let group = entry.group.as_deref().unwrap_or("group");
+
+        // Format: permissions links owner group size month day time name
+        // Example: drwxr-xr-x 1 root group 4096 Jan 01 12:00 dirname
+        let timestamp = format_timestamp(
+            entry.modified.unwrap_or(UNIX_EPOCH),
+            self.config.listing_timezone,
+        );
+
+        // Symlinks are shown as `name -> target`, like `ls -l` does.
+        let name = match &entry.symlink_target {
+            Some(target) => format!("{} -> {target}", entry.name),
+            None => entry.name.clone(),
+        };
+
+        format!(
+            "{} {} {:<8} {:<8} {:>12} {} {}",
+            perms, entry.nlink, owner, group, entry.size, timestamp, name
+        )
+    }
+
+    /// Builds a machine listing line (RFC 3659) with the facts selected by `OPTS MLST`.
+    fn format_facts(&self, entry: &FileInfo) -> String {
+        let mut facts = String::new();
+        for fact in &self.mlst_facts {
+            let value = match *fact {
+                "type" if entry.is_dir => String::from("dir"),
+                "type" => String::from("file"),
+                "size" => entry.size.to_string(),
+                "modify" => match entry.modified {
+                    Some(time) => format_mdtm(time),
+                    None => continue,
+                },
+                "perm" => self.fact_permissions(entry),
+                "UNIX.mode" => format!("{:04o}", entry.mode & 0o7777),
+                "UNIX.owner" => match &entry.owner {
+                    Some(owner) => owner.clone(),
+                    None => continue,
+                },
+                "UNIX.group" => match &entry.group {
+                    Some(group) => group.clone(),
+                    None => continue,
+                },
+                "UNIX.uid" => match entry.uid {
+                    Some(uid) => uid.to_string(),
+                    None => continue,
+                },
+                "UNIX.gid" => match entry.gid {
+                    Some(gid) => gid.to_string(),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            facts.push_str(&format!("{fact}={value};"));
+        }
+        format!("{facts} {}", entry.name)
+    }
+
+    /// Lists what the user may do with an entry, as the `perm` fact.
+    fn fact_permissions(&self, entry: &FileInfo) -> String {
+        let can_read = self.can_read();
+        let can_write = self.can_write() && !self.config.read_only;
+        let mut perm = String::new();
+        if entry.is_dir {
+            perm.push('e');
+            if can_read {
+                perm.push('l');
+            }
+            if can_write {
+                perm.push('c');
+            }
+        } else {
+            if can_read {
+                perm.push('r');
+            }
+            if can_write && !self.config.no_overwrite(&self.username) {
+                perm.push('w');
+            }
+        }
+        perm
+    }
+
+    /// Builds `ls -l` style lines for the given directory entries.
+    fn format_listing(&self, entries: &[FileInfo]) -> Vec<String> {
+        entries.iter().map(|e| self.format_entry(e)).collect()
+    }
+
+    /// Drops authorization, so the session waits for a new login.
+    fn log_out(&mut self) {
+        self.username.clear();
+        self.authorized = false;
+        self.current_dir = PathBuf::from("/");
+        self.rest_offset = 0;
+    }
+
+    /// Reads the next command line. Lines longer than `max_command_length` are dropped
+    /// and answered with an error, so a client can't make the buffer grow without bounds.
     async fn receive(&mut self) -> Result<String, ConnectionError> {
+        loop {
+            if let Some(end) = self.read_buffer.iter().position(|&b| b == b'\n') {
+                let mut line: Vec<u8> = self.read_buffer.drain(..=end).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+
+                if std::mem::take(&mut self.discarding_line)
+                    || line.len() > self.config.max_command_length
+                {
+                    reply!(self, 500, Message::LineTooLong);
+                    continue;
+                }
+                return Ok(self.decode_line(&line));
+            }
+
+            if self.read_buffer.len() > self.config.max_command_length {
+                // The rest of the line is dropped as it arrives, and reported once it ends.
+                self.read_buffer.clear();
+                self.discarding_line = true;
+            }
+
+            if self.control_closed {
+                return Err(ConnectionError::Disconnected);
+            }
+
+            let mut buf = [0u8; 1024];
+            let n = match self.connection.read(&mut buf).await {
+                Ok(0) => return Err(ConnectionError::Disconnected),
+                Ok(n) => n,
+                Err(e) => return Err(ConnectionError::ReadFailed(e.to_string())),
+            };
+            self.read_buffer.extend_from_slice(&buf[..n]);
+            self.read_buffered()?;
+        }
+    }
+
+    /// Moves data that already arrived into the read buffer. Reads stop short at the TCP
+    /// urgent mark, which clients set when sending `ABOR`, and data after it wouldn't wake
+    /// the reader again until the client sends something else.
+    fn read_buffered(&mut self) -> Result<(), ConnectionError> {
         let mut buf = [0u8; 1024];
-        let n = match self.connection.read(&mut buf).await {
-            Ok(0) => return Err(ConnectionError::Disconnected),
-            Ok(n) => n,
-            Err(e) => return Err(ConnectionError::ReadFailed(e.to_string())),
-        };
-        let data = String::from_utf8_lossy(&buf[..n]);
+        while self.read_buffer.len() <= self.config.max_command_length {
+            match self.connection.try_read_buffered(&mut buf) {
+                Ok(0) => {
+                    self.control_closed = true;
+                    break;
+                }
+                Ok(n) => self.read_buffer.extend_from_slice(&buf[..n]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {
+                    break;
+                }
+                Err(e) => return Err(ConnectionError::ReadFailed(e.to_string())),
+            }
+        }
+        Ok(())
+    }
 
-        Ok(data.to_string())
+    fn decode_line(&self, line: &[u8]) -> String {
+        let bytes = strip_telnet_commands(line);
+        if self.utf8 {
+            String::from_utf8_lossy(&bytes).to_string()
+        } else {
+            // Without UTF-8 every byte is taken as a Latin-1 character.
+            bytes.iter().map(|&b| char::from(b)).collect()
+        }
     }
 
     fn split_data(&self, data: String) -> Option<(String, String)> {
-        let trimmed = data.trim_end();
-        let splitted = trimmed
-            .splitn(2, ' ')
-            .map(String::from)
-            .collect::<Vec<String>>();
-        if splitted.is_empty() {
+        let trimmed = data.trim();
+        // Blank lines are sent by some clients as keepalives.
+        if trimmed.is_empty() {
             return None;
         }
 
-        let command = splitted.first().unwrap().to_owned();
-        let arg = if splitted.len() > 1 {
-            splitted.last().unwrap().to_owned()
-        } else {
-            String::new()
+        // Extra spaces after the verb are dropped, spaces inside the argument are kept,
+        // since they may be part of a file name.
+        let (command, arg) = trimmed.split_once(' ').unwrap_or((trimmed, ""));
+        Some((command.to_string(), arg.trim_start().to_string()))
+    }
+
+    /// Writes formatted reply text and flushes it, so replies are never held back
+    /// in a buffer while the client waits for them.
+    async fn send(&mut self, text: &str) -> Result<(), ConnectionError> {
+        let result = match self.connection.write_all(text.as_bytes()).await {
+            Ok(()) => self.connection.flush().await,
+            Err(e) => Err(e),
         };
-        Some((command, arg))
+        result.map_err(|e| ConnectionError::WriteError(e.to_string()))
     }
 
-    async fn reply(&mut self, code: u16, message: &str) -> Result<(), ConnectionError> {
+    async fn reply(&mut self, code: u16, message: impl ReplyText) -> Result<(), ConnectionError> {
+        let message = message.resolve(&self.config);
+        debug!(session_id=%self.id, code, reply=%message, "Sending reply.");
         let formatted_message = format!("{code} {message}\r\n");
-        if let Err(e) = self
-            .connection
-            .write_all(formatted_message.as_bytes())
-            .await
-        {
-            return Err(ConnectionError::WriteError(e.to_string()));
+        self.send(&formatted_message).await
+    }
+
+    /// Sends a multi-line reply, where the first line opens it with `code-` and the last one closes it with `code `.
+    async fn reply_multiline(
+        &mut self,
+        code: u16,
+        lines: &[String],
+    ) -> Result<(), ConnectionError> {
+        let Some((last, rest)) = lines.split_last() else {
+            return Ok(());
+        };
+        debug!(session_id=%self.id, code, lines = lines.len(), "Sending multi-line reply.");
+
+        let mut formatted_message = String::new();
+        for (i, line) in rest.iter().enumerate() {
+            if i == 0 {
+                formatted_message.push_str(&format!("{code}-{line}\r\n"));
+            } else {
+                formatted_message.push_str(&format!(" {line}\r\n"));
+            }
         }
-        Ok(())
+        formatted_message.push_str(&format!("{code} {last}\r\n"));
+
+        self.send(&formatted_message).await
     }
-    async fn reply_without_code(&mut self, message: &str) -> Result<(), ConnectionError> {
-        let formatted_message = format!("{message}\r\n");
-        if let Err(e) = self
-            .connection
-            .write_all(formatted_message.as_bytes())
-            .await
-        {
-            return Err(ConnectionError::WriteError(e.to_string()));
+
+    /// Sends the banner. Every line but the last is prefixed with `220-`, so text looking like a reply code can't end it early.
+    async fn greet(&mut self) -> Result<(), ConnectionError> {
+        let lines = self.config.banner_lines();
+        let Some((last, rest)) = lines.split_last() else {
+            return Ok(());
+        };
+
+        let mut formatted_message = String::new();
+        for line in rest {
+            formatted_message.push_str(&format!("220-{line}\r\n"));
         }
-        Ok(())
+        formatted_message.push_str(&format!("220 {last}\r\n"));
+
+        self.send(&formatted_message).await
     }
 
     #[must_use = "there could be a connection related error"]
-    pub async fn run_session(&mut self) -> Result<(), ConnectionError> {
-        self.reply(220, "Dock is welcoming you!").await?;
+    pub async fn run_session(
+        &mut self,
+        mut shutdown: broadcast::Receiver<()>,
+    ) -> Result<(), ConnectionError> {
+        self.greet().await?;
         loop {
-            let data = self.receive().await?;
+            let data = tokio::select! {
+                data = self.receive() => data?,
+                _ = shutdown.recv() => {
+                    reply!(self, 421, Message::ServerShutdown);
+                    return Err(ConnectionError::ServerShutdown);
+                }
+            };
             let (cmd, arg) = if let Some((c, a)) = self.split_data(data) {
                 (c, a)
             } else {
                 continue;
             };
 
-            let command: Commands = cmd.into();
+            // Verbs are case-insensitive, arguments are not.
+            let verb = cmd.to_ascii_uppercase();
+            debug!(session_id=%self.id, command=%verb, arg=%redact_argument(&verb, &arg), "Received command.");
+            let command: Commands = verb.into();
+            if command != Commands::Unknown {
+                self.unrecognized_commands = 0;
+            }
             self.handle_command(command, arg).await?;
+            if std::mem::take(&mut self.transfer_in_progress) {
+                self.reject_overlapping_transfers().await?;
+            }
         }
     }
 
     async fn handle_command(&mut self, cmd: Commands, arg: String) -> Result<(), ConnectionError> {
+        // Control characters have no place in file names, and would let clients forge log lines.
+        if PATH_COMMANDS.contains(&cmd) && arg.contains(['\0', '\r', '\n']) {
+            reply_ok!(self, 553, Message::FileNameNotAllowed);
+        }
+
+        // REST applies only to the transfer that immediately follows it, any other command cancels it.
+        // Commands setting up the transfer are let through, as some clients send them after REST.
+        let keeps_rest = matches!(
+            cmd,
+            Commands::Retrive
+                | Commands::Store
+                | Commands::Type
+                | Commands::Passive
+                | Commands::ExtendedPassive
+                | Commands::Port
+                | Commands::ExtendedPort
+        );
+        if self.rest_offset > 0 && !keeps_rest {
+            self.rest_offset = 0;
+            if matches!(
+                cmd,
+                Commands::List | Commands::MachineListDir | Commands::StoreUnique
+            ) {
+                reply_ok!(self, 554, Message::RestNotSupported);
+            }
+        }
+
         match cmd {
             Commands::User => {
-                if self.authorized {
-                    reply_ok!(self, 230, "Already logged in.");
-                }
-
                 if arg.is_empty() {
-                    reply_ok!(self, 501, "Username is required.");
+                    reply_ok!(self, 501, Message::UsernameRequired);
                 }
 
-                if !self.config.check_user(&arg) {
-                    reply_ok!(self, 530, "Authorization failed.");
+                // A new USER starts another login. As RFC 959 says, transfer parameters are kept.
+                if self.authorized {
+                    info!(session_id=%self.id, username=%self.username, new_username=%arg, "User is switching to another account.");
+                    self.log_out();
                 }
-
                 self.username = arg;
-                reply!(self, 331, "Password is required");
+                reply!(self, 331, Message::NeedPassword);
             }
             Commands::Password => {
                 if self.username.is_empty() {
-                    reply_ok!(self, 501, "Username is required.");
+                    reply_ok!(self, 501, Message::UsernameRequired);
                 }
 
                 if arg.is_empty() {
-                    reply_ok!(self, 501, "Password is required");
+                    reply_ok!(self, 501, Message::PasswordRequired);
                 }
 
-                if !self.config.check_password(&self.username, &arg) {
-                    reply_ok!(self, 530, "Authorization failed.");
+                if self.authenticator.authenticate(&self.username, &arg).await
+                    != AuthResult::Success
+                {
+                    self.failed_logins += 1;
+                    warn!(session_id=%self.id, ip=%self.client_ip(), username=%self.username, attempts=self.failed_logins, "Failed login attempt.");
+                    time::sleep(LOGIN_FAILURE_DELAY).await;
+
+                    let max_attempts = self.config.max_login_attempts;
+                    if max_attempts > 0 && self.failed_logins >= max_attempts {
+                        reply!(self, 421, Message::TooManyFailedLogins);
+                        return Err(ConnectionError::TooManyFailedLogins);
+                    }
+                    reply_ok!(self, 530, Message::AuthorizationFailed);
                 }
 
                 self.authorized = true;
-                info!(session_id=%self.id, username=%self.username, "User authorized.");
-                reply!(self, 230, "Login success.");
+                self.current_dir = self.start_dir().await;
+                let client = self.client.as_deref().unwrap_or("unknown");
+                info!(session_id=%self.id, ip=%self.client_ip(), username=%self.username, client=%client, "User authorized.");
+                METRICS.login();
+                self.observer.on_login(&self.id, &self.username).await;
+                reply!(self, 230, Message::LoginSuccess);
+            }
+            Commands::Account => {
+                // Accounts aren't used, but some clients always send one after login.
+                if self.authorized {
+                    reply_ok!(self, 230, Message::AlreadyLoggedIn);
+                }
+                reply!(self, 202, Message::AccountSuperfluous);
             }
             Commands::WorkingDir => {
                 reply!(
                     self,
                     257,
                     format!(
-                        "\"{}\" is the current directory.",
-                        self.current_dir.to_string_lossy()
+                        "{} is the current directory.",
+                        quote_path(&self.current_dir.to_string_lossy())
                     )
                     .as_str()
                 );
@@ -250,180 +816,556 @@ impl Session {
                 require_authorization!(self);
 
                 if arg.is_empty() {
-                    reply_ok!(self, 501, "Path is required");
+                    reply_ok!(self, 501, Message::PathRequired);
                 }
 
-                let new_virtual = self.current_dir.join(&arg).to_string_lossy().to_string();
-                let real_path = match self.resolve_path(new_virtual.clone()) {
-                    Ok(p) => p,
-                    Err(_) => {
-                        reply_ok!(self, 550, "Failed to change directory.");
+                let new_virtual = self.resolve_path(&arg);
+                let info = match self.vfs.metadata(&new_virtual).await {
+                    Ok(i) => i,
+                    Err(e) => {
+                        let message = match e.kind() {
+                            ErrorKind::NotFound => Message::PathNotFound,
+                            ErrorKind::PermissionDenied => Message::PermissionDenied,
+                            _ => Message::ChangeDirectoryFailed,
+                        };
+                        reply_fs_error!(self, e, 550, message);
                     }
                 };
 
-                if !real_path.is_dir() {
-                    reply_ok!(self, 550, "Not a directory.");
+                if !info.is_dir {
+                    reply_ok!(self, 550, Message::NotADirectory);
+                }
+
+                // Directory may exist but still be unreadable for the server. Opening it and
+                // reading the first entry tells that without loading the whole directory.
+                let readable = match self.vfs.open_dir(&new_virtual).await {
+                    Ok(mut entries) => entries.next_entry().await.map(|_| ()),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = readable
+                    && e.kind() == ErrorKind::PermissionDenied
+                {
+                    reply_ok!(self, 550, Message::PermissionDenied);
+                }
+
+                self.current_dir = new_virtual;
+                reply!(self, 250, Message::DirectoryChanged);
+            }
+            Commands::Client => {
+                if arg.is_empty() {
+                    reply_ok!(self, 501, Message::ClientNameRequired);
                 }
 
-                self.current_dir = PathBuf::from(new_virtual);
-                reply!(self, 250, "Directory changed.");
+                self.client = Some(arg);
+                reply!(self, 200, Message::Noted);
             }
             Commands::Option => {
                 if arg.is_empty() {
-                    reply_ok!(self, 501, "Argument is required");
+                    reply_ok!(self, 501, Message::ArgumentRequired);
                 }
 
-                match arg.as_str() {
-                    "UTF8" => {
-                        reply!(self, 200, "UTF-8 is enabled by default.");
+                let (option, value) = arg.split_once(' ').unwrap_or((&arg, ""));
+                match option.to_ascii_uppercase().as_str() {
+                    "UTF8" => match value.trim().to_ascii_uppercase().as_str() {
+                        "" | "ON" => {
+                            self.utf8 = true;
+                            reply!(self, 200, Message::Utf8Enabled);
+                        }
+                        "OFF" => {
+                            self.utf8 = false;
+                            reply!(self, 200, Message::Utf8Disabled);
+                        }
+                        _ => {
+                            reply!(self, 501, Message::Utf8Usage);
+                        }
+                    },
+                    "MLST" => {
+                        // Fact names are case-insensitive.
+                        let requested: Vec<&str> = value.trim().split(';').collect();
+                        self.mlst_facts = MLST_FACTS
+                            .iter()
+                            .copied()
+                            .filter(|f| requested.iter().any(|r| r.eq_ignore_ascii_case(f)))
+                            .collect();
+                        let facts: String =
+                            self.mlst_facts.iter().map(|f| format!("{f};")).collect();
+                        reply!(self, 200, format!("MLST OPTS {facts}").as_str());
+                    }
+                    "HASH" => {
+                        if value.trim().is_empty() {
+                            reply_ok!(self, 200, self.hash_algorithm.name());
+                        }
+                        let Some(algorithm) = HashAlgorithm::parse(value) else {
+                            reply_ok!(self, 501, Message::UnknownAlgorithm);
+                        };
+                        self.hash_algorithm = algorithm;
+                        reply!(self, 200, algorithm.name());
                     }
                     _ => {
-                        reply!(self, 501, "Unknown option");
+                        reply!(self, 501, Message::UnknownOption);
                     }
                 }
             }
-            Commands::List => {
+            Commands::List | Commands::MachineListDir => {
                 require_authorization!(self);
-                let mut data_connection = self
-                    .open_data_connection()
-                    .await
-                    .map_err(|e| ConnectionError::DataConnectionFailed(e.to_string()))?;
-                reply!(self, 150, "Listing of directory");
-
-                let virtual_path = if arg.is_empty() {
-                    self.current_dir.to_string_lossy().to_string()
-                } else {
-                    self.current_dir.join(&arg).to_string_lossy().to_string()
+                require_read_access!(self);
+                require_data_channel!(self);
+
+                let Some(mut data_connection) =
+                    self.open_data_connection(Message::OpeningListing).await?
+                else {
+                    reply_ok!(self, 425, Message::CantOpenDataConnection);
                 };
 
-                let real_path = match self.resolve_path(virtual_path) {
-                    Ok(p) => p,
-                    Err(_) => {
-                        reply!(self, 550, "Failed to list directory.");
+                // Wildcards in the last segment filter entries of its directory, e.g. `LIST *.txt`.
+                let (dir, pattern) = match arg.rsplit_once('/') {
+                    Some((dir, name)) if pattern::is_pattern(name) => {
+                        (if dir.is_empty() { "/" } else { dir }, Some(name))
+                    }
+                    None if pattern::is_pattern(&arg) => ("", Some(arg.as_str())),
+                    _ => (arg.as_str(), None),
+                };
+                let pattern = pattern.map(str::to_string);
+
+                let virtual_path = self.resolve_path(dir);
+                let mut entries = match self.vfs.open_dir(&virtual_path).await {
+                    Ok(e) => e,
+                    Err(e) => {
+                        let _ = data_connection.shutdown().await;
+                        match e.kind() {
+                            ErrorKind::NotFound
+                            | ErrorKind::NotADirectory
+                            | ErrorKind::PermissionDenied => {
+                                self.reply_fs_error(&e, 550, Message::ListFailed).await?;
+                            }
+                            _ => {
+                                reply!(self, 451, Message::LocalError);
+                            }
+                        }
                         return Ok(());
                     }
                 };
 
-                // Pseudo values. I dont think clients really care about it.
-                let links = "1";
-                let owner = "root";
-                let group = "group";
-
-                let mut entries = fs::read_dir(real_path)
-                    .await
-                    .map_err(|_| ConnectionError::FileSystemError)?;
-
-                let mut listing_strings: Vec<String> = Vec::new();
-
-                while let Some(entry) = entries
-                    .next_entry()
-                    .await
-                    .map_err(|_| ConnectionError::FileSystemError)?
-                {
-                    let name = entry.file_name().to_string_lossy().to_string();
-                    let metadata = entry
-                        .metadata()
+                // Lines are sent as entries are read, so memory use doesn't grow with directory size.
+                let mut data_connection = BufWriter::new(data_connection);
+                loop {
+                    let entry = match entries.next_entry().await {
+                        Ok(Some(entry)) => entry,
+                        Ok(None) => break,
+                        Err(_) => {
+                            let _ = data_connection.shutdown().await;
+                            reply_ok!(self, 451, Message::LocalError);
+                        }
+                    };
+                    if pattern
+                        .as_deref()
+                        .is_some_and(|p| !pattern::matches(p, &entry.name))
+                    {
+                        continue;
+                    }
+                    let line = match cmd {
+                        Commands::MachineListDir => self.format_facts(&entry),
+                        _ => self.format_entry(&entry),
+                    };
+                    // A client closing the data connection only aborts the listing, not the session.
+                    if let Err(e) = data_connection
+                        .write_all(format!("{line}\r\n").as_bytes())
                         .await
-                        .map_err(|_| ConnectionError::FileSystemError)?;
-
-                    let is_dir = metadata.is_dir();
-                    let size = metadata.len();
-                    let perms = Self::format_unix_permissions(is_dir, &metadata.permissions());
-
-                    // Format: permissions links owner group size month day time name
-                    // Example: drwxr-xr-x 1 root group 4096 Jan 01 12:00 dirname
-                    let modified = metadata
-                        .modified()
-                        .ok()
-                        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
-                        .map(|d| d.as_secs())
-                        .unwrap_or(0);
-
-                    // Simple timestamp formatting (could be improved with chrono)
-                    let timestamp = format_timestamp(modified);
-
-                    let line = format!(
-                        "{} {} {} {} {:>12} {} {}\r\n",
-                        perms, links, owner, group, size, timestamp, name
-                    );
-                    listing_strings.push(line);
+                    {
+                        warn!(session_id=%self.id, reason=%e, "Listing aborted, data connection closed.");
+                        reply_ok!(self, 426, Message::TransferAborted);
+                    }
                 }
 
-                // Send listing through data connection
-                for entry in listing_strings {
-                    data_connection
-                        .write_all(entry.as_bytes())
-                        .await
-                        .map_err(|e| ConnectionError::WriteError(e.to_string()))?;
+                // Shutting down flushes the rest of the listing, which can fail the same way.
+                if let Err(e) = data_connection.shutdown().await {
+                    warn!(session_id=%self.id, reason=%e, "Listing aborted, data connection closed.");
+                    reply_ok!(self, 426, Message::TransferAborted);
                 }
+                reply!(self, 226, Message::TransferComplete);
+            }
+            Commands::MachineListEntry => {
+                require_authorization!(self);
+                require_read_access!(self);
 
-                let _ = data_connection.shutdown().await;
-                reply!(self, 226, "Transfer complete.");
+                let virtual_path = self.resolve_path(&arg);
+                let mut info = match self.vfs.metadata(&virtual_path).await {
+                    Ok(i) => i,
+                    Err(e) => {
+                        reply_fs_error!(self, e, 550, Message::FileUnavailable);
+                    }
+                };
+                // Entry is named by its full path, as it's not inside a listed directory.
+                let path = virtual_path.to_string_lossy().to_string();
+                info.name = path.clone();
+
+                // The facts line must start with a space, which multi-line replies add to inner lines.
+                let lines = vec![
+                    format!("Listing {path}"),
+                    self.format_facts(&info),
+                    String::from("End."),
+                ];
+                self.reply_multiline(250, &lines).await?;
+            }
+            Commands::ProtectionSize | Commands::DataProtection => {
+                // RFC 4217 requires a TLS secured control connection before these,
+                // which is never the case as long as AUTH TLS isn't supported.
+                reply!(self, 503, Message::SecurityNotCompleted);
+            }
+            Commands::Reinitialize => {
+                self.log_out();
+                self.transfer_type = TransferType::default();
+                self.transfer_mode = TransferMode::default();
+                self.file_structure = FileStructure::default();
+                self.utf8 = true;
+                self.mlst_facts = MLST_FACTS.to_vec();
+                self.hash_algorithm = HashAlgorithm::default();
+                self.umask = None;
+                self.active_addr = None;
+                self.passive_listener = None;
+                reply!(self, 220, Message::SessionReinitialized);
+            }
+            Commands::Abort => {
+                // Downloads pick `ABOR` up themselves, so getting here means nothing is running.
+                reply!(self, 225, Message::NoTransferToAbort);
             }
             Commands::Quit => {
-                reply!(self, 221, "Bye!");
+                reply!(self, 221, Message::Bye);
                 return Err(ConnectionError::ClosedByQuit);
             }
             Commands::Features => {
-                reply!(self, 211, "Features");
-                for i in SERVER_FEATURES {
-                    self.reply_without_code(i).await?;
+                let mut lines = vec![String::from("Features:")];
+                lines.extend(self.features());
+                lines.push(String::from("End"));
+                self.reply_multiline(211, &lines).await?;
+            }
+            Commands::Site => {
+                let (sub, sub_arg) = self.split_data(arg).unwrap_or_default();
+                let sub = sub.to_ascii_uppercase();
+
+                match sub.as_str() {
+                    "HELP" => {
+                        if !sub_arg.is_empty() {
+                            let name = sub_arg.trim().to_ascii_uppercase();
+                            match site_command_help(&name) {
+                                Some(help) => {
+                                    reply!(self, 214, help);
+                                }
+                                None => {
+                                    reply!(
+                                        self,
+                                        501,
+                                        format!("Unknown SITE command {name}.").as_str()
+                                    );
+                                }
+                            }
+                            return Ok(());
+                        }
+
+                        let names: Vec<&str> = SITE_COMMAND_TABLE.iter().map(|(n, _)| *n).collect();
+                        let lines = vec![
+                            String::from("The following SITE commands are recognized:"),
+                            names.join(" "),
+                            String::from("Help OK."),
+                        ];
+                        self.reply_multiline(214, &lines).await?;
+                    }
+                    "UMASK" => {
+                        require_authorization!(self);
+
+                        let umask = u32::from_str_radix(sub_arg.trim(), 8)
+                            .ok()
+                            .filter(|m| *m <= 0o777);
+                        let Some(umask) = umask else {
+                            reply_ok!(self, 500, Message::InvalidUmask);
+                        };
+
+                        self.umask = Some(umask);
+                        reply!(self, 200, format!("UMASK set to {umask:04o}.").as_str());
+                    }
+                    "" => {
+                        reply!(self, 501, Message::ArgumentRequired);
+                    }
+                    _ => {
+                        reply!(self, 502, Message::UnknownSiteCommand);
+                    }
+                }
+            }
+            Commands::Status => {
+                if arg.is_empty() {
+                    let peer = self.client_ip();
+                    let user = if self.authorized {
+                        format!("Logged in as {}", self.username)
+                    } else {
+                        String::from("Not logged in")
+                    };
+                    let lines = vec![
+                        format!("Dock FTP Server {} status:", env!("CARGO_PKG_VERSION")),
+                        format!("Connected to {peer}"),
+                        user,
+                        format!("Current directory: {}", self.current_dir.to_string_lossy()),
+                        format!("TYPE: {}", self.transfer_type.name()),
+                        format!("MODE: {}", self.transfer_mode.code()),
+                        format!("STRU: {}", self.file_structure.code()),
+                        format!("UTF-8: {}", if self.utf8 { "on" } else { "off" }),
+                        String::from("End of status."),
+                    ];
+                    self.reply_multiline(211, &lines).await?;
+                    return Ok(());
+                }
+
+                require_authorization!(self);
+                require_read_access!(self);
+
+                let virtual_path = self.resolve_path(&arg);
+                let entries = match self.vfs.read_dir(&virtual_path).await {
+                    Ok(e) => e,
+                    Err(e) => {
+                        reply_fs_error!(self, e, 550, Message::ListFailed);
+                    }
+                };
+                let listing = self.format_listing(&entries);
+
+                let mut lines = Vec::with_capacity(listing.len() + 2);
+                lines.push(format!("Status of {arg}:"));
+                lines.extend(listing);
+                lines.push(String::from("End of status."));
+                self.reply_multiline(213, &lines).await?;
+            }
+            Commands::Help => {
+                if !arg.is_empty() {
+                    let verb = arg.trim().to_ascii_uppercase();
+                    match command_help(&verb) {
+                        Some(help) => {
+                            reply!(self, 214, help);
+                        }
+                        None => {
+                            reply!(self, 501, format!("Unknown command {verb}.").as_str());
+                        }
+                    }
+                    return Ok(());
                 }
-                reply!(self, 211, "End");
+
+                let verbs: Vec<&str> = COMMAND_TABLE.iter().map(|(v, _, _)| *v).collect();
+                let mut lines = vec![String::from("The following commands are recognized:")];
+                lines.extend(verbs.chunks(8).map(|c| c.join(" ")));
+                lines.push(String::from("Help OK."));
+                self.reply_multiline(214, &lines).await?;
+            }
+            Commands::StructureMount => {
+                // Served root is fixed, there is nothing else to mount.
+                reply!(self, 502, Message::NotImplemented);
             }
             Commands::Unknown => {
-                reply!(self, 502, "Unknown command.");
+                self.unrecognized_commands += 1;
+                reply!(self, 500, Message::UnknownCommand);
+                // Peers sending nothing but junk, like port scanners, aren't kept around.
+                if self.unrecognized_commands >= MAX_UNRECOGNIZED_COMMANDS {
+                    reply!(self, 421, Message::TooManyUnknownCommands);
+                    return Err(ConnectionError::TooManyUnrecognizedCommands);
+                }
             }
             Commands::System => {
-                reply!(self, 215, "UNIX Type: L8");
+                reply!(self, 215, Message::SystemType);
             }
             Commands::Type => {
-                reply!(self, 200, "OK");
+                if arg.is_empty() {
+                    reply_ok!(self, 501, Message::ArgumentRequired);
+                }
+
+                match TransferType::parse(&arg) {
+                    Some(t) => {
+                        self.transfer_type = t;
+                        reply!(self, 200, format!("Type set to {}.", t.name()).as_str());
+                    }
+                    None => {
+                        reply!(self, 504, Message::TypeNotSupported);
+                    }
+                }
+            }
+            Commands::Mode => {
+                if arg.is_empty() {
+                    reply_ok!(self, 501, Message::ArgumentRequired);
+                }
+
+                match TransferMode::parse(&arg) {
+                    Some(m) => {
+                        self.transfer_mode = m;
+                        reply!(self, 200, format!("Mode set to {}.", m.code()).as_str());
+                    }
+                    None if matches!(arg.trim().to_ascii_uppercase().as_str(), "B" | "C") => {
+                        reply!(self, 504, Message::UnsupportedMode);
+                    }
+                    None => {
+                        reply!(self, 501, Message::UnknownMode);
+                    }
+                }
+            }
+            Commands::Structure => {
+                if arg.is_empty() {
+                    reply_ok!(self, 501, Message::ArgumentRequired);
+                }
+
+                match FileStructure::parse(&arg) {
+                    Some(s) => {
+                        self.file_structure = s;
+                        reply!(
+                            self,
+                            200,
+                            format!("Structure set to {}.", s.code()).as_str()
+                        );
+                    }
+                    None if matches!(arg.trim().to_ascii_uppercase().as_str(), "R" | "P") => {
+                        reply!(self, 504, Message::UnsupportedStructure);
+                    }
+                    None => {
+                        reply!(self, 501, Message::UnknownStructure);
+                    }
+                }
             }
             Commands::Size => {
                 require_authorization!(self);
+                require_read_access!(self);
                 if arg.is_empty() {
-                    reply_ok!(self, 501, "Path is required");
+                    reply_ok!(self, 501, Message::PathRequired);
                 }
 
-                let virtual_path = self.current_dir.join(&arg).to_string_lossy().to_string();
-                let real_path = match self.resolve_path(virtual_path) {
-                    Ok(p) => p,
-                    Err(_) => {
-                        reply!(self, 550, "File unavailable.");
-                        return Ok(());
+                let virtual_path = self.resolve_path(&arg);
+                let info = match self.vfs.metadata(&virtual_path).await {
+                    Ok(i) => i,
+                    Err(e) => {
+                        reply_fs_error!(self, e, 550, Message::FileUnavailable);
                     }
                 };
 
-                let metadata = fs::metadata(real_path)
-                    .await
-                    .map_err(|_| ConnectionError::FileSystemError)?;
-                if !metadata.is_file() {
-                    reply_ok!(self, 550, "Not a file.");
+                if info.is_dir {
+                    reply_ok!(self, 550, Message::NotAFile);
                 }
-                reply!(self, 213, format!("{}", metadata.len()).as_str());
+                reply!(self, 213, format!("{}", info.size).as_str());
             }
-            Commands::ChangeDirectoryUp => {
+            Commands::Hash => {
                 require_authorization!(self);
+                require_read_access!(self);
+                if arg.is_empty() {
+                    reply_ok!(self, 501, Message::PathRequired);
+                }
 
-                let parent = if let Some(p) = self.current_dir.parent() {
-                    p.to_path_buf()
-                } else {
+                let virtual_path = self.resolve_path(&arg);
+                let algorithm = self.hash_algorithm;
+                let Some((digest, end)) =
+                    self.file_digest(&virtual_path, algorithm, 0, None).await?
+                else {
+                    return Ok(());
+                };
+                // Format from draft-bryan-ftpext-hash: <algorithm> <start>-<end> <digest> <path>
+                reply!(
+                    self,
+                    213,
+                    format!("{} 0-{end} {digest} {arg}", algorithm.name()).as_str()
+                );
+            }
+            Commands::CrcChecksum | Commands::Md5Checksum => {
+                require_authorization!(self);
+                require_read_access!(self);
+                let Some((path, start, end)) = split_checksum_args(&arg) else {
+                    reply_ok!(self, 501, Message::PathRequired);
+                };
+
+                let algorithm = match cmd {
+                    Commands::CrcChecksum => HashAlgorithm::Crc32,
+                    _ => HashAlgorithm::Md5,
+                };
+                let virtual_path = self.resolve_path(path);
+                let Some((digest, _)) = self
+                    .file_digest(&virtual_path, algorithm, start, end)
+                    .await?
+                else {
+                    return Ok(());
+                };
+                reply!(self, 213, digest.as_str());
+            }
+            Commands::ModificationTime => {
+                require_authorization!(self);
+                require_read_access!(self);
+                if arg.is_empty() {
+                    reply_ok!(self, 501, Message::PathRequired);
+                }
+
+                let virtual_path = self.resolve_path(&arg);
+                let info = match self.vfs.metadata(&virtual_path).await {
+                    Ok(i) => i,
+                    Err(e) => {
+                        reply_fs_error!(self, e, 550, Message::FileUnavailable);
+                    }
+                };
+
+                if info.is_dir {
+                    reply_ok!(self, 550, Message::NotAFile);
+                }
+                let Some(modified) = info.modified else {
+                    reply_ok!(self, 550, Message::ModificationTimeUnavailable);
+                };
+                reply!(self, 213, format_mdtm(modified).as_str());
+            }
+            Commands::SetModifyTime => {
+                require_authorization!(self);
+                require_write_access!(self);
+
+                let (timestamp, path) = self.split_data(arg).unwrap_or_default();
+                if path.is_empty() {
+                    reply_ok!(self, 501, Message::TimestampAndPathRequired);
+                }
+
+                let Some(time) = parse_mdtm(&timestamp) else {
+                    reply_ok!(self, 501, Message::InvalidTimestamp);
+                };
+
+                let virtual_path = self.resolve_path(&path);
+                match self.vfs.metadata(&virtual_path).await {
+                    Ok(info) if !info.is_dir => {}
+                    Ok(_) => {
+                        reply_ok!(self, 550, Message::NotAFile);
+                    }
+                    Err(e) => {
+                        reply_fs_error!(self, e, 550, Message::FileUnavailable);
+                    }
+                }
+
+                if let Err(e) = self.vfs.set_modified(&virtual_path, time).await {
+                    reply_fs_error!(self, e, 550, Message::SetModificationTimeFailed);
+                }
+                reply!(
+                    self,
+                    213,
+                    format!("Modify={}; {path}", format_mdtm(time)).as_str()
+                );
+            }
+            Commands::ChangeDirectoryUp => {
+                require_authorization!(self);
+
+                let parent = if let Some(p) = self.current_dir.parent() {
+                    p.to_path_buf()
+                } else {
                     PathBuf::from("/")
                 };
                 self.current_dir = parent;
-                reply!(self, 250, "Directory changed.");
+                reply!(self, 250, Message::DirectoryChanged);
             }
             Commands::Port => {
                 require_authorization!(self);
+                require_active_mode!(self);
+
+                if self.is_ipv6_control() {
+                    reply_ok!(self, 501, Message::UseEprt);
+                }
 
                 if arg.is_empty() {
-                    reply_ok!(self, 501, "Address is required");
+                    reply_ok!(self, 501, Message::AddressRequired);
                 }
 
                 let splitted: Vec<String> = arg.split(',').map(String::from).collect();
                 if splitted.len() != 6 {
-                    reply_ok!(self, 501, "Syntax error in arguments");
+                    reply_ok!(self, 501, Message::SyntaxError);
                 }
 
                 let h1 = splitted[0].trim();
@@ -436,12 +1378,17 @@ impl Session {
                     splitted[5].trim().parse::<u16>(),
                 ) {
                     if p1 > 255 || p2 > 255 {
-                        reply_ok!(self, 501, "Invalid port");
+                        reply_ok!(self, 501, Message::InvalidPort);
                     }
 
                     let port = p1 * 256 + p2;
-                    let ip_string = format!("{h1}.{h2}.{h3}.{h4}:{port}");
-                    let addr: SocketAddr = ip_string.parse().unwrap();
+                    let Ok(ip) = format!("{h1}.{h2}.{h3}.{h4}").parse::<Ipv4Addr>() else {
+                        reply_ok!(self, 501, Message::SyntaxError);
+                    };
+                    if !self.is_data_address_allowed(IpAddr::V4(ip)) {
+                        reply_ok!(self, 501, Message::IllegalPortAddress);
+                    }
+                    let addr = SocketAddr::new(IpAddr::V4(ip), port);
 
                     if let Some(pasv) = self.passive_listener.take() {
                         drop(pasv);
@@ -449,30 +1396,53 @@ impl Session {
                     }
 
                     self.active_addr = Some(addr);
-                    reply!(self, 200, "PORT command success.");
+                    reply!(self, 200, Message::PortSuccess);
                 } else {
-                    reply!(self, 501, "Syntax error in arguments ");
+                    reply!(self, 501, Message::SyntaxError);
                 }
             }
             Commands::Passive => {
                 require_authorization!(self);
-                let ln = TcpListener::bind("0.0.0.0:0")
-                    .await
-                    .map_err(|_| ConnectionError::FileSystemError)?;
-                let addr: SocketAddr = ln
-                    .local_addr()
-                    .map_err(|_| ConnectionError::FileSystemError)?;
-                let port = addr.port();
+                require_passive_mode!(self);
 
-                self.passive_listener = Some(ln);
+                if self.is_ipv6_control() {
+                    reply_ok!(self, 425, Message::UseEpsv);
+                }
 
-                let ip = match self
+                // Listen only on the interface client has reached us on, unless configured otherwise.
+                let control_ip = match self
                     .connection
                     .local_addr()
                     .map_err(|_| ConnectionError::FileSystemError)?
+                    .ip()
+                    .to_canonical()
                 {
-                    SocketAddr::V4(v4) if !v4.ip().is_unspecified() => *v4.ip(),
-                    _ => Ipv4Addr::new(127, 0, 0, 1),
+                    IpAddr::V4(v4) if !v4.is_unspecified() => v4,
+                    _ => Ipv4Addr::LOCALHOST,
+                };
+                let bind_ip = match self.config.data_address {
+                    Some(IpAddr::V4(ip)) => ip,
+                    Some(IpAddr::V6(_)) => {
+                        reply_ok!(self, 425, Message::UseEpsv);
+                    }
+                    None => control_ip,
+                };
+                let Some((ln, port)) = self.listen_passive(IpAddr::V4(bind_ip)).await else {
+                    reply_ok!(self, 425, Message::CantOpenDataConnection);
+                };
+
+                self.passive_listener = Some(ln);
+
+                // A listener on all interfaces is advertised with the address client has reached.
+                // Behind NAT clients have to be told the public address instead.
+                let ip = if bind_ip.is_unspecified() {
+                    control_ip
+                } else {
+                    bind_ip
+                };
+                let ip = match &self.masquerade {
+                    Some(resolver) => resolver.resolve().await.unwrap_or(ip),
+                    None => ip,
                 };
 
                 let [h1, h2, h3, h4] = ip.octets();
@@ -489,203 +1459,1063 @@ impl Session {
                     .as_str()
                 );
             }
+            Commands::ExtendedPort => {
+                require_authorization!(self);
+                require_active_mode!(self);
+
+                if arg.is_empty() {
+                    reply_ok!(self, 501, Message::AddressRequired);
+                }
+
+                // Format: <d><proto><d><address><d><port><d>, e.g. |2|::1|6446|
+                let Some(delimiter) = arg.chars().next() else {
+                    reply_ok!(self, 501, Message::SyntaxError);
+                };
+                let splitted: Vec<&str> = arg.split(delimiter).collect();
+                if splitted.len() != 5 {
+                    reply_ok!(self, 501, Message::SyntaxError);
+                }
+
+                let ip: IpAddr = match (splitted[1], splitted[2].parse::<IpAddr>()) {
+                    ("1", Ok(ip @ IpAddr::V4(_))) | ("2", Ok(ip @ IpAddr::V6(_))) => ip,
+                    ("1" | "2", _) => {
+                        reply_ok!(self, 501, Message::SyntaxError);
+                    }
+                    _ => {
+                        reply_ok!(self, 522, Message::ProtocolNotSupported);
+                    }
+                };
+                let Ok(port) = splitted[3].parse::<u16>() else {
+                    reply_ok!(self, 501, Message::InvalidPort);
+                };
+                if !self.is_data_address_allowed(ip) {
+                    reply_ok!(self, 501, Message::IllegalPortAddress);
+                }
+
+                self.passive_listener = None;
+                self.active_addr = Some(SocketAddr::new(ip, port));
+                reply!(self, 200, Message::EprtSuccess);
+            }
+            Commands::ExtendedPassive => {
+                require_authorization!(self);
+                require_passive_mode!(self);
+
+                if arg.eq_ignore_ascii_case("ALL") {
+                    reply_ok!(self, 200, Message::EpsvAllSuccess);
+                }
+
+                // The reply carries no address, so clients connect to the one of the control connection.
+                let local_ip = match self.config.data_address {
+                    Some(ip) => ip,
+                    None => self
+                        .connection
+                        .local_addr()
+                        .map_err(|_| ConnectionError::FileSystemError)?
+                        .ip(),
+                };
+                let Some((ln, port)) = self.listen_passive(local_ip).await else {
+                    reply_ok!(self, 425, Message::CantOpenDataConnection);
+                };
+
+                self.active_addr = None;
+                self.passive_listener = Some(ln);
+                reply!(
+                    self,
+                    229,
+                    format!("Entering Extended Passive Mode (|||{port}|)").as_str()
+                );
+            }
             Commands::Rest => {
                 require_authorization!(self);
 
                 if arg.is_empty() {
-                    reply_ok!(self, 501, "Argument is required.");
+                    reply_ok!(self, 501, Message::ArgumentRequired);
                 }
 
-                self.rest_offset = arg.parse().unwrap();
-                reply!(self, 350, "Restarting at sepcific bytes.");
+                // `REST 0` is accepted too and simply means starting from the beginning.
+                let Ok(offset) = arg.parse() else {
+                    reply_ok!(self, 501, Message::SyntaxError);
+                };
+                self.rest_offset = offset;
+                reply!(self, 350, Message::RestartAccepted);
             }
             Commands::Retrive => {
                 require_authorization!(self);
 
-                if !self.config.can_user_read(&self.username) {
-                    reply_ok!(self, 501, "No permission to read.");
-                }
+                // REST applies only to the transfer command that immediately follows it.
+                let rest_offset = std::mem::take(&mut self.rest_offset);
+
+                require_read_access!(self);
 
                 if arg.is_empty() {
-                    reply_ok!(self, 501, "Argument is required.");
+                    reply_ok!(self, 501, Message::ArgumentRequired);
                 }
 
-                let virtual_path = self.current_dir.join(&arg).to_string_lossy().to_string();
-                let real_path = match self.resolve_path(virtual_path) {
-                    Ok(p) => p,
-                    Err(_) => {
-                        reply_ok!(self, 550, "File unavailable.");
+                let virtual_path = self.resolve_path(&arg);
+                let info = match self.vfs.metadata(&virtual_path).await {
+                    Ok(i) => i,
+                    Err(e) => {
+                        reply_fs_error!(self, e, 550, Message::FileUnavailable);
                     }
                 };
-                let mut file = File::open(&real_path)
-                    .await
-                    .map_err(|_| ConnectionError::FileSystemError)?;
-                let meta = file
-                    .metadata()
-                    .await
-                    .map_err(|_| ConnectionError::FileSystemError)?;
-                let size = meta.len();
 
-                if self.rest_offset > 0 {
-                    if self.rest_offset >= size {
-                        self.rest_offset = 0;
-                        reply_ok!(self, 550, "Invalid restart position.");
-                    }
-                    file.seek(SeekFrom::Start(self.rest_offset))
-                        .await
-                        .map_err(|_| ConnectionError::FileSystemError)?;
+                if info.is_dir {
+                    reply_ok!(self, 550, Message::NotAFile);
+                }
+
+                if rest_offset > 0 && rest_offset >= info.size {
+                    reply_ok!(self, 550, Message::InvalidRestartPosition);
                 }
 
-                if let Ok(mut data) = self.open_data_connection().await {
-                    reply!(self, 150, "Ready to transfer...");
-                    info!(session_id=%self.id, file=%real_path.to_string_lossy() , username=%self.username, "User is retriving file.");
-                    io::copy(&mut file, &mut data).await.map_err(|_| {
-                        ConnectionError::DataConnectionFailed(String::from("I/O operation failed"))
-                    })?;
+                require_data_channel!(self);
+
+                let mut file = match self.vfs.open(&virtual_path, rest_offset).await {
+                    Ok(f) => f,
+                    Err(e) => {
+                        reply_fs_error!(self, e, 550, Message::FileUnavailable);
+                    }
+                };
+
+                // Mentioning the offset lets clients see their restart was taken into account.
+                let preliminary = if rest_offset > 0 {
+                    self.config
+                        .message(Message::RestartingDownload)
+                        .replace("{offset}", &rest_offset.to_string())
+                } else {
+                    self.config.message(Message::OpeningDownload).to_string()
+                };
+                if let Some(mut data) = self.open_data_connection(preliminary.as_str()).await? {
+                    info!(session_id=%self.id, file=%virtual_path.to_string_lossy() , username=%self.username, "User is retriving file.");
+                    let rate_limit = self.config.download_rate_limit(&self.username);
+                    let started = Instant::now();
+                    let mut progress = Progress::new(
+                        Arc::clone(&self.observer),
+                        &self.id,
+                        virtual_path.clone(),
+                        Some(info.size - rest_offset),
+                    );
+                    // `sendfile` can't report progress, so it's skipped when progress is observed.
+                    let local = match progress {
+                        Some(_) => None,
+                        None => self.local_file(&virtual_path, rate_limit).await,
+                    };
+                    let (transfer_type, buffer_size) =
+                        (self.transfer_type, self.config.transfer_buffer_size);
+                    let transfer = async {
+                        #[cfg(target_os = "linux")]
+                        if let Some(local) = local {
+                            return transfer::send_file(&local, rest_offset, &data).await;
+                        }
+
+                        #[cfg(not(target_os = "linux"))]
+                        let _ = local;
+
+                        transfer::copy_to_client(
+                            &mut file,
+                            &mut data,
+                            transfer_type,
+                            rate_limit,
+                            buffer_size,
+                            progress.as_mut(),
+                        )
+                        .await
+                    };
+                    let result = self.run_abortable(transfer).await?;
                     let _ = data.shutdown().await;
-                    self.rest_offset = 0;
-                    reply!(self, 226, "Done.");
+                    let bytes = match result {
+                        Some(Ok(bytes)) => bytes,
+                        Some(Err(e)) => {
+                            warn!(session_id=%self.id, username=%self.username, path=%virtual_path.to_string_lossy(), reason=%e, "Download failed.");
+                            if is_disconnect(&e) {
+                                reply_ok!(self, 426, Message::TransferAborted);
+                            }
+                            reply_ok!(self, 451, Message::LocalError);
+                        }
+                        None => {
+                            info!(session_id=%self.id, username=%self.username, path=%virtual_path.to_string_lossy(), "Download aborted by client.");
+                            reply!(self, 426, Message::TransferAborted);
+                            reply_ok!(self, 226, Message::AbortSuccessful);
+                        }
+                    };
+                    self.log_transfer("download", &virtual_path, bytes, started);
+                    METRICS.sent(bytes);
+                    self.observer
+                        .on_download_complete(&self.id, &self.username, &virtual_path, bytes)
+                        .await;
+                    reply!(self, 226, Message::DownloadComplete);
                 } else {
-                    reply!(self, 425, "Cant open data connection.");
+                    reply!(self, 425, Message::CantOpenDataConnection);
                 }
             }
             Commands::Store => {
                 require_authorization!(self);
 
-                if !self.config.can_user_write(&self.username) {
-                    reply_ok!(self, 550, "No permission to write.");
-                }
+                let rest_offset = std::mem::take(&mut self.rest_offset);
+
+                require_write_access!(self);
 
                 if arg.is_empty() {
-                    reply_ok!(self, 501, "Argument is required.");
+                    reply_ok!(self, 501, Message::ArgumentRequired);
                 }
 
                 if DISALLOWED_FILENAMES.contains(&arg.as_str()) {
-                    reply_ok!(self, 553, "File name not allowed.");
+                    reply_ok!(self, 553, Message::FileNameNotAllowed);
                 }
 
-                let file_path = self.get_real_path().join(arg);
-                let parent_dir = file_path.parent().unwrap_or(Path::new(""));
-                fs::create_dir_all(parent_dir)
-                    .await
-                    .map_err(|_| ConnectionError::FileSystemError)?;
-                let mut file = File::create(&file_path)
-                    .await
-                    .map_err(|_| ConnectionError::FileSystemError)?;
+                require_data_channel!(self);
 
-                if let Ok(mut data) = self.open_data_connection().await {
-                    reply!(self, 150, "Ready to receive.");
+                let file_path = self.resolve_path(&arg);
+                let existing = self.vfs.metadata(&file_path).await.ok();
+                if existing.is_some() && self.config.no_overwrite(&self.username) {
+                    reply_ok!(self, 553, Message::FileExists);
+                }
+                if existing.as_ref().is_some_and(|e| e.is_dir) {
+                    reply_ok!(self, 550, Message::FileUnavailable);
+                }
+                let old_size = existing.as_ref().map_or(0, |e| e.size);
+                let replaced = old_size.saturating_sub(rest_offset);
+                let limit = self.upload_limit(replaced, rest_offset).await;
+                if let Some(limit) = limit
+                    && limit.bytes() == 0
+                {
+                    reply_ok!(self, 552, limit.message());
+                }
+
+                // Uploads from the start are written next to the target and replace it once
+                // complete, so a failed upload leaves an existing file as it was.
+                let write_path = if rest_offset == 0 {
+                    temp_upload_path(&file_path)
+                } else {
+                    file_path.clone()
+                };
+                let mut file = match self.vfs.create(&write_path, rest_offset).await {
+                    Ok(f) => f,
+                    Err(e) if rest_offset > 0 => {
+                        reply_fs_error!(self, e, 550, Message::InvalidRestartPosition);
+                    }
+                    Err(e) => {
+                        reply_fs_error!(self, e, 550, Message::FileUnavailable);
+                    }
+                };
+                match &existing {
+                    None => self.apply_umask(&write_path).await,
+                    Some(info) if rest_offset == 0 => {
+                        let _ = self.vfs.set_mode(&write_path, info.mode).await;
+                    }
+                    Some(_) => {}
+                }
+
+                if let Some(mut data) = self.open_data_connection(Message::OpeningUpload).await? {
                     info!(session_id=%self.id, file=%file_path.to_string_lossy() , username=%self.username, "User is sending file.");
-                    io::copy(&mut data, &mut file).await.map_err(|_| {
-                        ConnectionError::DataConnectionFailed(String::from("I/O operation failed"))
-                    })?;
+                    let rate_limit = self.config.upload_rate_limit(&self.username);
+                    let started = Instant::now();
+                    let mut progress = Progress::new(
+                        Arc::clone(&self.observer),
+                        &self.id,
+                        file_path.clone(),
+                        None,
+                    );
+                    let result = transfer::copy_from_client(
+                        &mut data,
+                        &mut file,
+                        self.transfer_type,
+                        rate_limit,
+                        limit.map(UploadLimit::bytes),
+                        self.config.transfer_buffer_size,
+                        progress.as_mut(),
+                    )
+                    .await;
+                    let _ = data.shutdown().await;
+                    drop(file);
+                    let mut result = self
+                        .upload_result(result, &write_path, limit, rest_offset)
+                        .await;
+                    if let Ok(Some(_)) = result
+                        && write_path != file_path
+                        && let Err(e) = self.vfs.rename(&write_path, &file_path).await
+                    {
+                        warn!(session_id=%self.id, username=%self.username, path=%file_path.to_string_lossy(), reason=%e, "Failed to replace file with upload.");
+                        let _ = self.vfs.remove(&write_path).await;
+                        result = self
+                            .reply_fs_error(&e, 451, Message::LocalError)
+                            .await
+                            .map(|()| None);
+                    }
+                    self.track_usage(&file_path, old_size).await;
+                    let Some(bytes) = result? else {
+                        return Ok(());
+                    };
+
+                    self.log_transfer("upload", &file_path, bytes, started);
+                    METRICS.received(bytes);
+                    self.observer
+                        .on_upload_complete(&self.id, &self.username, &file_path, bytes)
+                        .await;
+                    reply!(self, 226, Message::TransferComplete);
+                } else {
+                    drop(file);
+                    if rest_offset == 0 {
+                        let _ = self.vfs.remove(&write_path).await;
+                    }
+                    self.track_usage(&file_path, old_size).await;
+                    reply!(self, 425, Message::CantOpenDataConnection);
+                }
+            }
+            Commands::Allocate => {
+                require_authorization!(self);
+
+                // Format: <size> [R <record size>], record size only matters for record structure.
+                let Some(Ok(size)) = arg.split_whitespace().next().map(str::parse::<u64>) else {
+                    reply_ok!(self, 501, Message::SizeRequired);
+                };
+
+                if let Some(limit) = self.upload_limit(0, 0).await
+                    && size > limit.bytes()
+                {
+                    reply_ok!(self, 552, limit.message());
+                }
+                reply!(self, 200, Message::AllocationAccepted);
+            }
+            Commands::Available => {
+                require_authorization!(self);
+
+                let virtual_path = self.resolve_path(&arg);
+                let available = match self.vfs.available_space(&virtual_path).await {
+                    Ok(a) => a,
+                    Err(e) if e.kind() == ErrorKind::Unsupported => {
+                        reply_ok!(self, 502, Message::NotImplemented);
+                    }
+                    Err(e) => {
+                        reply_fs_error!(self, e, 550, Message::PathNotFound);
+                    }
+                };
+                // Quota may leave less room than the disk has.
+                let available = match self.remaining_quota(0).await {
+                    Some(remaining) => available.min(remaining),
+                    None => available,
+                };
+                reply!(self, 213, available.to_string().as_str());
+            }
+            Commands::StoreUnique => {
+                require_authorization!(self);
+                require_write_access!(self);
+                require_data_channel!(self);
+
+                // Only the file name part of the hint is used, the file always lands in current directory.
+                let hint = Path::new(&arg)
+                    .file_name()
+                    .map(|n| n.to_string_lossy().to_string())
+                    .filter(|n| !DISALLOWED_FILENAMES.contains(&n.as_str()));
+
+                let (file_name, file_path) = loop {
+                    let name = match &hint {
+                        Some(h) => format!("{h}.{}", cuid2::cuid()),
+                        None => cuid2::cuid(),
+                    };
+                    let path = self.resolve_path(&name);
+                    if self.vfs.metadata(&path).await.is_err() {
+                        break (name, path);
+                    }
+                };
 
-                    self.rest_offset = 0;
+                let limit = self.upload_limit(0, 0).await;
+                if let Some(limit) = limit
+                    && limit.bytes() == 0
+                {
+                    reply_ok!(self, 552, limit.message());
+                }
+
+                let mut file = match self.vfs.create_new(&file_path).await {
+                    Ok(f) => f,
+                    Err(e) => {
+                        reply_fs_error!(self, e, 550, Message::FileUnavailable);
+                    }
+                };
+                self.apply_umask(&file_path).await;
+
+                let message = format!("FILE: {file_name}");
+                if let Some(mut data) = self.open_data_connection(message.as_str()).await? {
+                    info!(session_id=%self.id, file=%file_path.to_string_lossy() , username=%self.username, "User is sending unique file.");
+                    let rate_limit = self.config.upload_rate_limit(&self.username);
+                    let started = Instant::now();
+                    let mut progress = Progress::new(
+                        Arc::clone(&self.observer),
+                        &self.id,
+                        file_path.clone(),
+                        None,
+                    );
+                    let result = transfer::copy_from_client(
+                        &mut data,
+                        &mut file,
+                        self.transfer_type,
+                        rate_limit,
+                        limit.map(UploadLimit::bytes),
+                        self.config.transfer_buffer_size,
+                        progress.as_mut(),
+                    )
+                    .await;
                     let _ = data.shutdown().await;
-                    reply!(self, 226, "Transfer complete.");
+                    drop(file);
+                    let result = self.upload_result(result, &file_path, limit, 0).await;
+                    self.track_usage(&file_path, 0).await;
+                    let Some(bytes) = result? else {
+                        return Ok(());
+                    };
+
+                    self.log_transfer("upload", &file_path, bytes, started);
+                    METRICS.received(bytes);
+                    self.observer
+                        .on_upload_complete(&self.id, &self.username, &file_path, bytes)
+                        .await;
+                    reply!(self, 250, file_name.as_str());
                 } else {
-                    reply!(self, 425, "Cant open data connection.");
+                    drop(file);
+                    let _ = self.vfs.remove(&file_path).await;
+                    reply!(self, 425, Message::CantOpenDataConnection);
                 }
             }
         }
         Ok(())
     }
 
-    async fn open_data_connection(&mut self) -> Result<TcpStream, anyhow::Error> {
+    /// Turns a path given by client into a clean absolute virtual path.
+    fn resolve_path(&self, path: &str) -> PathBuf {
+        normalize_path(&self.current_dir.join(path))
+    }
+
+    fn can_read(&self) -> bool {
+        self.authenticator
+            .permissions(&self.username)
+            .is_some_and(|p| p.can_read())
+    }
+
+    fn can_write(&self) -> bool {
+        self.authenticator
+            .permissions(&self.username)
+            .is_some_and(|p| p.can_write())
+    }
+
+    /// Opens the file directly when it can be sent with `sendfile`, which skips
+    /// the filesystem abstraction for binary downloads without rate limit.
+    #[cfg(target_os = "linux")]
+    async fn local_file(&self, path: &Path, rate_limit: u64) -> Option<std::fs::File> {
+        if self.transfer_type != TransferType::Image || rate_limit > 0 {
+            return None;
+        }
+        let file = tokio::fs::File::open(self.vfs.local_path(path).await?).await;
+        Some(file.ok()?.into_std().await)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn local_file(&self, _path: &Path, _rate_limit: u64) -> Option<std::fs::File> {
+        None
+    }
+
+    /// Runs a transfer while watching the control connection for `ABOR`. Other commands
+    /// sent meanwhile stay buffered until the transfer is over. Returns `None` if it was aborted.
+    async fn run_abortable<F>(
+        &mut self,
+        transfer: F,
+    ) -> Result<Option<std::io::Result<u64>>, ConnectionError>
+    where
+        F: Future<Output = std::io::Result<u64>>,
+    {
+        tokio::pin!(transfer);
+        loop {
+            if self.take_abort() {
+                return Ok(None);
+            }
+
+            // Reading stops once the buffer is full, so a client can't make it grow without bounds.
+            // A client that has closed the control connection still gets its transfer finished.
+            let watch =
+                !self.control_closed && self.read_buffer.len() <= self.config.max_command_length;
+            let mut buf = [0u8; 1024];
+            tokio::select! {
+                result = &mut transfer => return Ok(Some(result)),
+                read = self.connection.read(&mut buf), if watch => match read {
+                    Ok(0) => self.control_closed = true,
+                    Ok(n) => {
+                        self.read_buffer.extend_from_slice(&buf[..n]);
+                        self.read_buffered()?;
+                    }
+                    Err(e) => return Err(ConnectionError::ReadFailed(e.to_string())),
+                },
+            }
+        }
+    }
+
+    /// Refuses transfer commands that were sent while a transfer ran, instead of starting them
+    /// once it's over. Other commands sent meanwhile stay in the buffer and are handled in order.
+    async fn reject_overlapping_transfers(&mut self) -> Result<(), ConnectionError> {
+        let mut start = 0;
+        while let Some(len) = self.read_buffer[start..].iter().position(|&b| b == b'\n') {
+            let end = start + len + 1;
+            let line = self.decode_line(&self.read_buffer[start..end]);
+            let command = self
+                .split_data(line)
+                .map(|(verb, _)| Commands::from(verb.to_ascii_uppercase()));
+            if command.is_some_and(|c| TRANSFER_COMMANDS.contains(&c)) {
+                self.read_buffer.drain(start..end);
+                reply!(self, 503, Message::BadSequence);
+            } else {
+                start = end;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes an `ABOR` command from lines waiting in the read buffer, leaving the rest
+    /// in order. Telnet sequences clients send before it are ignored.
+    fn take_abort(&mut self) -> bool {
+        let mut start = 0;
+        while let Some(len) = self.read_buffer[start..].iter().position(|&b| b == b'\n') {
+            let end = start + len + 1;
+            let line = strip_telnet_commands(&self.read_buffer[start..end]);
+            if line.trim_ascii().eq_ignore_ascii_case(b"ABOR") {
+                self.read_buffer.drain(start..end);
+                return true;
+            }
+            start = end;
+        }
+        false
+    }
+
+    /// Replies to a failed filesystem operation. A missing root gets its own reply,
+    /// so it isn't mistaken for a missing file, and so does denied access.
+    async fn reply_fs_error(
+        &mut self,
+        error: &std::io::Error,
+        code: u16,
+        message: impl ReplyText,
+    ) -> Result<(), ConnectionError> {
+        if vfs::is_root_unavailable(error) {
+            warn!(session_id=%self.id, "Served root directory is unavailable.");
+            return self.reply(550, Message::RootUnavailable).await;
+        }
+        if error.kind() == ErrorKind::PermissionDenied {
+            return self.reply(550, Message::PermissionDenied).await;
+        }
+        self.reply(code, message).await
+    }
+
+    /// Picks the tighter of quota and maximum file size for an upload starting at `offset`.
+    async fn upload_limit(&self, replaced: u64, offset: u64) -> Option<UploadLimit> {
+        let quota = self.remaining_quota(replaced).await.map(UploadLimit::Quota);
+        let max_size = self.config.max_upload_size;
+        let size = (max_size > 0).then(|| UploadLimit::FileSize(max_size.saturating_sub(offset)));
+        match (quota, size) {
+            (Some(q), Some(s)) => Some(if s.bytes() < q.bytes() { s } else { q }),
+            (q, s) => q.or(s),
+        }
+    }
+
+    /// Returns how many more bytes user may store, or `None` without a quota.
+    /// `replaced` bytes are about to be overwritten, so they don't count as used.
+    async fn remaining_quota(&self, replaced: u64) -> Option<u64> {
+        let quota = self.config.quota_bytes(&self.username);
+        if quota == 0 {
+            return None;
+        }
+
+        let used = self
+            .disk_usage
+            .get(self.vfs.as_ref())
+            .await
+            .saturating_sub(replaced);
+        Some(quota.saturating_sub(used))
+    }
+
+    /// Finds where the logged in user starts. Falls back to root if configured directory is missing.
+    async fn start_dir(&self) -> PathBuf {
+        let root = PathBuf::from("/");
+        let Some(start_dir) = self.config.start_dir(&self.username) else {
+            return root;
+        };
+
+        let path = normalize_path(Path::new(start_dir));
+        match self.vfs.metadata(&path).await {
+            Ok(info) if info.is_dir => path,
+            _ => {
+                warn!(session_id=%self.id, username=%self.username, start_dir=%start_dir, "Start directory is not available, using root.");
+                root
+            }
+        }
+    }
+
+    /// Updates disk usage after an upload has changed the file from `old_size` bytes.
+    async fn track_usage(&self, path: &Path, old_size: u64) {
+        let new_size = self.vfs.metadata(path).await.map_or(0, |info| info.size);
+        self.disk_usage.adjust(old_size, new_size).await;
+    }
+
+    /// Turns the outcome of an upload that started at `offset` into the number of bytes stored.
+    /// On failure replies with the reason and returns `None`. Partial files are removed
+    /// if the upload started from an empty file, so resumed uploads keep what they already had.
+    /// Resumed uploads that went over the maximum size are cut back to where they started.
+    async fn upload_result(
+        &mut self,
+        result: std::io::Result<u64>,
+        path: &Path,
+        limit: Option<UploadLimit>,
+        offset: u64,
+    ) -> Result<Option<u64>, ConnectionError> {
+        let error = match result {
+            Ok(bytes) => return Ok(Some(bytes)),
+            Err(e) => e,
+        };
+
+        warn!(session_id=%self.id, username=%self.username, path=%path.to_string_lossy(), reason=%error, "Upload failed.");
+        let too_large = error.kind() == ErrorKind::FileTooLarge
+            && matches!(limit, Some(UploadLimit::FileSize(_)));
+        if offset == 0 {
+            let _ = self.vfs.remove(path).await;
+        } else if too_large {
+            // Reopening at the offset drops whatever was written past it.
+            let _ = self.vfs.create(path, offset).await;
+        }
+
+        match (error.kind(), limit) {
+            (ErrorKind::FileTooLarge, Some(limit)) => {
+                self.reply(552, limit.message()).await?;
+            }
+            _ if is_disconnect(&error) => {
+                self.reply(426, Message::TransferAborted).await?;
+            }
+            _ => {
+                self.reply(451, Message::LocalError).await?;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Sets permissions of a newly created file according to session's umask.
+    /// Without one, the file keeps the mode given by the process umask.
+    async fn apply_umask(&self, path: &Path) {
+        if let Some(umask) = self.umask {
+            let _ = self.vfs.set_mode(path, 0o666 & !umask).await;
+        }
+    }
+
+    fn log_transfer(&self, direction: &str, path: &Path, bytes: u64, started: Instant) {
+        info!(
+            session_id=%self.id,
+            username=%self.username,
+            path=%path.to_string_lossy(),
+            bytes,
+            duration_ms = started.elapsed().as_millis() as u64,
+            direction=%direction,
+            "Transfer complete."
+        );
+    }
+
+    /// Checks if control connection goes over IPv6, where `PASV` and `PORT` can't describe addresses.
+    fn is_ipv6_control(&self) -> bool {
+        self.connection
+            .local_addr()
+            .is_ok_and(|a| a.ip().to_canonical().is_ipv6())
+    }
+
+    /// Checks if active mode may connect to the given address. Unless configured otherwise,
+    /// it has to be the client's own address, so the server can't be used to reach third-party hosts (FTP bounce).
+    fn is_data_address_allowed(&self, ip: IpAddr) -> bool {
+        self.config.allow_foreign_data_addresses
+            || self
+                .client_addr()
+                .is_some_and(|client| client.ip().to_canonical() == ip.to_canonical())
+    }
+
+    /// Builds the `FEAT` lines for features available to this session.
+    fn features(&self) -> Vec<String> {
+        let ipv6 = self.is_ipv6_control();
+        let mut features: Vec<String> = SERVER_FEATURES
+            .iter()
+            .filter(|(_, cmd)| COMMAND_TABLE.iter().any(|(_, c, _)| c == cmd))
+            .filter(|(_, cmd)| !(ipv6 && matches!(cmd, Commands::Passive | Commands::Port)))
+            .filter(|(_, cmd)| {
+                !(self.config.passive_only
+                    && matches!(cmd, Commands::Port | Commands::ExtendedPort))
+            })
+            .filter(|(_, cmd)| {
+                !(self.config.active_only
+                    && matches!(cmd, Commands::Passive | Commands::ExtendedPassive))
+            })
+            .map(|(f, cmd)| match cmd {
+                Commands::MachineListEntry => format!("{f} {}", self.mlst_feature()),
+                Commands::Hash => format!("{f} {}", self.hash_feature()),
+                _ => f.to_string(),
+            })
+            .collect();
+        let site_commands: Vec<&str> = SITE_COMMAND_TABLE.iter().map(|(n, _)| *n).collect();
+        features.push(format!("SITE {}", site_commands.join(";")));
+        features
+    }
+
+    /// Lists supported `MLST` facts, marking the ones selected for this session with `*`.
+    fn mlst_feature(&self) -> String {
+        MLST_FACTS
+            .iter()
+            .map(|f| {
+                let marker = if self.mlst_facts.contains(f) { "*" } else { "" };
+                format!("{f}{marker};")
+            })
+            .collect()
+    }
+
+    /// Lists supported digest algorithms, marking the one selected for this session with `*`.
+    fn hash_feature(&self) -> String {
+        HASH_ALGORITHMS
+            .iter()
+            .map(|a| {
+                let marker = if *a == self.hash_algorithm { "*" } else { "" };
+                format!("{}{marker}", a.name())
+            })
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+
+    /// Computes digest of the file's bytes from `start` up to `end`, or up to the end of file.
+    /// Replies with the reason and returns `None` if it can't be done.
+    /// Otherwise returns the digest and the offset where hashing stopped.
+    async fn file_digest(
+        &mut self,
+        path: &Path,
+        algorithm: HashAlgorithm,
+        start: u64,
+        end: Option<u64>,
+    ) -> Result<Option<(String, u64)>, ConnectionError> {
+        let info = match self.vfs.metadata(path).await {
+            Ok(i) => i,
+            Err(e) => {
+                self.reply_fs_error(&e, 550, Message::FileUnavailable)
+                    .await?;
+                return Ok(None);
+            }
+        };
+        if info.is_dir {
+            self.reply(550, Message::NotAFile).await?;
+            return Ok(None);
+        }
+
+        let end = end.map_or(info.size, |e| e.min(info.size));
+        if start > end {
+            self.reply(501, Message::InvalidByteRange).await?;
+            return Ok(None);
+        }
+
+        let digest = match self.vfs.open(path, start).await {
+            Ok(file) => checksum::digest(&mut file.take(end - start), algorithm).await,
+            Err(e) => Err(e),
+        };
+        match digest {
+            Ok(digest) => Ok(Some((digest, end))),
+            Err(e) => {
+                warn!(session_id=%self.id, file=%path.to_string_lossy(), reason=%e, "Failed to compute file digest.");
+                self.reply(451, Message::LocalError).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Binds a listener for a passive data connection and returns it with its port. The address
+    /// may come from configuration and stop being usable, e.g. once the interface is gone,
+    /// which only fails this data connection.
+    async fn listen_passive(&self, ip: IpAddr) -> Option<(TcpListener, u16)> {
+        let bound = TcpListener::bind(SocketAddr::new(ip, 0))
+            .await
+            .and_then(|ln| {
+                let port = ln.local_addr()?.port();
+                Ok((ln, port))
+            });
+        match bound {
+            Ok(bound) => Some(bound),
+            Err(e) => {
+                warn!(session_id=%self.id, address=%ip, reason=%e, "Failed to open passive data listener.");
+                None
+            }
+        }
+    }
+
+    /// Opens data connection and sends the preliminary reply: `125` if client has already connected,
+    /// `150` if connection is about to be opened. Returns `None` if connection couldn't be opened.
+    async fn open_data_connection(
+        &mut self,
+        message: impl ReplyText,
+    ) -> Result<Option<TcpStream>, ConnectionError> {
+        let data = self.connect_data(message).await?;
+        self.transfer_in_progress = data.is_some();
+        Ok(data)
+    }
+
+    async fn connect_data(
+        &mut self,
+        message: impl ReplyText,
+    ) -> Result<Option<TcpStream>, ConnectionError> {
         let timeout = Duration::from_secs(10);
 
         // Active Mode (PORT)
         if let Some(addr) = self.active_addr.take() {
-            let stream = time::timeout(timeout, TcpStream::connect(&addr))
-                .await
-                .map_err(|_| anyhow!("data connection timeout"))?
-                .map_err(anyhow::Error::from)?;
-            return Ok(stream);
+            self.reply(150, message).await?;
+            let stream = time::timeout(timeout, TcpStream::connect(&addr)).await;
+            return Ok(stream.ok().and_then(Result::ok));
         }
 
         // Passive Mode (PASV)
-        let listener = match self.passive_listener.take() {
-            Some(l) => l,
-            None => bail!("use PASV or PORT first"),
-        };
-
-        let accept_fn = async move {
-            let (stream, _) = listener.accept().await?;
-            Ok::<TcpStream, anyhow::Error>(stream)
+        let Some(listener) = self.passive_listener.take() else {
+            return Ok(None);
         };
 
-        let stream = time::timeout(timeout, accept_fn)
-            .await
-            .map_err(|_| anyhow!("data connection timeout"))??;
+        // Zero timeout still polls accept once, which tells if client is already connected.
+        if let Ok(Ok((stream, _))) = time::timeout(Duration::ZERO, listener.accept()).await {
+            self.reply(125, message).await?;
+            return Ok(Some(stream));
+        }
 
-        Ok(stream)
+        self.reply(150, message).await?;
+        let stream = time::timeout(timeout, listener.accept()).await;
+        Ok(stream.ok().and_then(Result::ok).map(|(stream, _)| stream))
     }
 
     pub fn id(&self) -> &String {
         &self.id
     }
+}
 
-    fn get_real_path(&mut self) -> PathBuf {
-        let temp_cwd = &self.current_dir;
-        let temp_cwd_string = temp_cwd.to_string_lossy().to_string();
-        let temp_cwd_trimmed = temp_cwd_string.trim_start_matches('/');
-        Path::new(&self.config.root).join(temp_cwd_trimmed)
-    }
-
-    fn resolve_path(&self, path: String) -> Result<PathBuf, ConnectionError> {
-        let root = Path::new(&self.config.root);
-        let candidate = root.join(path.strip_prefix("/").unwrap_or(&path));
-        let canon = candidate
-            .canonicalize()
-            .map_err(|_| ConnectionError::FileSystemError)?;
-
-        #[cfg(unix)]
-        if !canon.starts_with(root) {
-            return Err(ConnectionError::FileSystemError);
+/// Removes Telnet IAC sequences (e.g. the `IAC IP IAC DM` sent before `ABOR`) from control channel data.
+fn strip_telnet_commands(data: &[u8]) -> Vec<u8> {
+    const IAC: u8 = 255;
+    const SB: u8 = 250;
+    const SE: u8 = 240;
+    const WILL: u8 = 251;
+    const DONT: u8 = 254;
+
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] != IAC {
+            out.push(data[i]);
+            i += 1;
+            continue;
         }
-        #[cfg(not(unix))]
-        {
-            let canon_format = format!("\\\\?\\{}", root.to_string_lossy());
-            if !canon.starts_with(canon_format) {
-                return Err(ConnectionError::FileSystemError);
+
+        match data.get(i + 1) {
+            // Escaped 0xFF data byte.
+            Some(&IAC) => {
+                out.push(IAC);
+                i += 2;
+            }
+            // Option negotiation carries one extra byte.
+            Some(&(WILL..=DONT)) => i += 3,
+            // Subnegotiation lasts until IAC SE.
+            Some(&SB) => {
+                i += 2;
+                while i < data.len() && !(data[i] == IAC && data.get(i + 1) == Some(&SE)) {
+                    i += 1;
+                }
+                i += 2;
             }
+            Some(_) => i += 2,
+            None => i += 1,
         }
+    }
+    out
+}
+
+/// Checks if a transfer failed because the data connection was closed or stalled.
+fn is_disconnect(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::TimedOut
+    )
+}
+
+/// Quotes a path for a `257` reply, doubling quotes inside it as RFC 959 requires.
+fn quote_path(path: &str) -> String {
+    format!("\"{}\"", path.replace('"', "\"\""))
+}
+
+/// Converts days since Unix epoch to a (year, month, day) civil date in UTC.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Howard Hinnant's days_from_civil inverse.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Converts a (year, month, day) civil date in UTC to days since Unix epoch.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = i64::from((month + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
 
-        Ok(canon)
+/// Parses `YYYYMMDDHHMMSS[.sss]` in UTC, as used by `MFMT`.
+fn parse_mdtm(value: &str) -> Option<SystemTime> {
+    let digits = value.split('.').next()?;
+    if digits.len() != 14 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
     }
+
+    let field = |range: std::ops::Range<usize>| digits[range].parse::<u32>().ok();
+    let year = i64::from(field(0..4)?);
+    let (month, day) = (field(4..6)?, field(6..8)?);
+    let (hour, minute, second) = (field(8..10)?, field(10..12)?, field(12..14)?);
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    // Days past the end of a month, like February 31st, would otherwise roll over into the next one.
+    let days = days_from_civil(year, month, day);
+    if civil_from_days(days) != (year, month, day) {
+        return None;
+    }
+
+    let secs = days * 86_400 + i64::from(hour * 3600 + minute * 60 + second);
+    let secs = u64::try_from(secs).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
 }
 
-/// Formats a Unix timestamp into a simple date-time string
-/// Format: "Mon DD HH:MM" or "Mon DD  YYYY" for older files
-fn format_timestamp(timestamp: u64) -> String {
-    use std::time::{SystemTime, UNIX_EPOCH};
+/// Splits `<path> [<start> [<end>]]` arguments of `XCRC` and `XMD5`. Path may be quoted.
+fn split_checksum_args(arg: &str) -> Option<(&str, u64, Option<u64>)> {
+    let mut path = arg.trim();
+    let mut offsets = Vec::with_capacity(2);
+    while offsets.len() < 2
+        && let Some((rest, last)) = path.rsplit_once(' ')
+        && let Ok(offset) = last.parse::<u64>()
+    {
+        offsets.insert(0, offset);
+        path = rest.trim_end();
+    }
 
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
+    let path = path
+        .strip_prefix('"')
+        .and_then(|p| p.strip_suffix('"'))
+        .unwrap_or(path);
+    if path.is_empty() {
+        return None;
+    }
+    Some((
+        path,
+        offsets.first().copied().unwrap_or(0),
+        offsets.get(1).copied(),
+    ))
+}
 
-    let six_months = 60 * 60 * 24 * 180;
-    let time = UNIX_EPOCH + std::time::Duration::from_secs(timestamp);
+/// Makes a virtual path absolute and clean. Repeated separators and `.` segments
+/// are dropped, `..` goes one level up but never above root.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut resolved = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => resolved.push(part),
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            _ => {}
+        }
+    }
+    resolved
+}
 
-    // Simple formatting - in production you'd use chrono
-    let datetime = time.duration_since(UNIX_EPOCH).unwrap().as_secs();
-    let days_since_epoch = datetime / (60 * 60 * 24);
+/// Picks a hidden name in the same directory for an upload that replaces `path` when done.
+fn temp_upload_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.{}.part", cuid2::cuid()))
+}
 
-    // Simplified date calculation
-    let months = [
+/// Formats time as `YYYYMMDDHHMMSS` in UTC, as used by `MDTM` (RFC 3659).
+fn format_mdtm(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let seconds_of_day = secs.rem_euclid(86_400);
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        (seconds_of_day / 60) % 60,
+        seconds_of_day % 60
+    )
+}
+
+/// Formats modification time like `ls -l` does: "Mon DD HH:MM" for recent files
+/// and "Mon DD  YYYY" for ones older than six months or from the future.
+fn format_timestamp(time: SystemTime, timezone: ListingTimezone) -> String {
+    const MONTHS: [&str; 12] = [
         "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
     ];
-    let month_idx = ((days_since_epoch / 30) % 12) as usize;
-    let day = (days_since_epoch % 30) + 1;
+    const SIX_MONTHS: i64 = 60 * 60 * 24 * 182;
+
+    let unix_secs = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0)
+    };
+    let secs = unix_secs(time);
+    let now = unix_secs(SystemTime::now());
 
-    let hour = (datetime / 3600) % 24;
-    let minute = (datetime / 60) % 60;
+    let shifted = match timezone {
+        ListingTimezone::Utc => secs,
+        ListingTimezone::Local => secs + local_utc_offset(secs),
+    };
+    let (year, month, day) = civil_from_days(shifted.div_euclid(86_400));
+    let seconds_of_day = shifted.rem_euclid(86_400);
+    let month = MONTHS[month as usize - 1];
 
-    if now - timestamp > six_months {
-        let year = 1970 + (days_since_epoch / 365);
-        format!("{} {:2}  {:4}", months[month_idx], day, year)
+    if secs > now || now - secs > SIX_MONTHS {
+        format!("{month} {day:2}  {year:4}")
     } else {
-        format!("{} {:2} {:02}:{:02}", months[month_idx], day, hour, minute)
+        format!(
+            "{month} {day:2} {:02}:{:02}",
+            seconds_of_day / 3600,
+            (seconds_of_day / 60) % 60
+        )
     }
 }
+
+/// Returns offset of the server's time zone from UTC at the given time, in seconds.
+/// The time zone is loaded on the first call and kept for the lifetime of the process.
+#[cfg(unix)]
+pub(crate) fn local_utc_offset(secs: i64) -> i64 {
+    let time = secs as libc::time_t;
+    let mut tm = std::mem::MaybeUninit::<libc::tm>::uninit();
+    // `localtime_r` takes daylight saving time into account and, unlike `localtime`, is thread safe.
+    if unsafe { libc::localtime_r(&time, tm.as_mut_ptr()) }.is_null() {
+        return 0;
+    }
+    // `tm_gmtoff` is 32 bits wide on some targets.
+    #[allow(clippy::useless_conversion)]
+    i64::from(unsafe { tm.assume_init() }.tm_gmtoff)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn local_utc_offset(_secs: i64) -> i64 {
+    0
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,1552 @@
+use std::{
+    fs,
+    net::Ipv6Addr,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use tempfile::TempDir;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream},
+    net::{TcpListener, TcpStream},
+    sync::broadcast,
+    task::JoinHandle,
+};
+
+use super::*;
+use crate::{
+    config::{ConfigBuilder, Permissions, User},
+    observer::NoopObserver,
+    vfs::DiskFs,
+};
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Configuration with a user for every permission. Passwords are `secret`.
+fn config(root: &Path) -> ConfigBuilder {
+    Config::builder("127.0.0.1:0", root.to_string_lossy())
+        .user(User::new("alice", "secret", Permissions::All))
+        .user(User::new("bob", "secret", Permissions::All))
+        .user(User::new("reader", "secret", Permissions::Read))
+        .user(User::new("writer", "secret", Permissions::Write))
+}
+
+/// Client side of a session. Control connection is in-memory unless a test needs TCP.
+struct Client<C = DuplexStream> {
+    stream: BufReader<C>,
+    session: JoinHandle<Result<(), ConnectionError>>,
+    /// The `220` reply the session started with.
+    greeting: String,
+    _shutdown: broadcast::Sender<()>,
+}
+
+impl Client {
+    async fn start(builder: ConfigBuilder) -> Self {
+        Self::start_with(builder, Arc::new(NoopObserver)).await
+    }
+
+    async fn start_with(builder: ConfigBuilder, observer: Arc<dyn SessionObserver>) -> Self {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        Client::connect(builder, client, server, observer, |s| s).await
+    }
+
+    /// Starts a session serving files from another backend.
+    async fn start_with_vfs(builder: ConfigBuilder, vfs: Arc<dyn VirtualFs>) -> Self {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let observer = Arc::new(NoopObserver);
+        Client::connect(builder, client, server, observer, |mut s| {
+            s.vfs = vfs;
+            s
+        })
+        .await
+    }
+
+    /// Starts a session for a client whose address was reported in a PROXY header.
+    async fn start_behind_proxy(builder: ConfigBuilder, addr: SocketAddr) -> Self {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let observer = Arc::new(NoopObserver);
+        Client::connect(builder, client, server, observer, |s| {
+            s.with_client_addr(addr)
+        })
+        .await
+    }
+}
+
+impl Client<TcpStream> {
+    /// Serves the session over a loopback TCP connection, for behavior specific to sockets.
+    async fn start_tcp(builder: ConfigBuilder) -> Self {
+        Self::start_tcp_on(builder, IpAddr::V4(Ipv4Addr::LOCALHOST)).await
+    }
+
+    /// Serves the session over TCP on the given local address.
+    async fn start_tcp_on(builder: ConfigBuilder, ip: IpAddr) -> Self {
+        let listener = tokio::net::TcpListener::bind((ip, 0)).await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        crate::server::set_oob_inline(&server);
+        Client::connect(builder, client, server, Arc::new(NoopObserver), |s| s).await
+    }
+}
+
+impl<C: AsyncRead + AsyncWrite + Unpin> Client<C> {
+    async fn connect<S: ControlStream + Sync + 'static>(
+        builder: ConfigBuilder,
+        client: C,
+        server: S,
+        observer: Arc<dyn SessionObserver>,
+        setup: impl FnOnce(Session<S>) -> Session<S>,
+    ) -> Self {
+        let config = builder.build().unwrap();
+        let vfs = Arc::new(DiskFs::new(&config.root));
+        let (shutdown, receiver) = broadcast::channel(1);
+        let mut session = setup(Session::new(
+            &String::from("test"),
+            server,
+            config.clone(),
+            Arc::new(config),
+            vfs,
+            observer,
+        ));
+        let session = tokio::spawn(async move { session.run_session(receiver).await });
+
+        let mut client = Self {
+            stream: BufReader::new(client),
+            session,
+            greeting: String::new(),
+            _shutdown: shutdown,
+        };
+        client.greeting = client.expect(220).await;
+        client
+    }
+
+    async fn send(&mut self, line: &str) {
+        self.send_raw(format!("{line}\r\n").as_bytes()).await;
+    }
+
+    async fn send_raw(&mut self, data: &[u8]) {
+        self.stream.write_all(data).await.unwrap();
+    }
+
+    /// Reads a whole reply, multi-line ones included.
+    async fn reply(&mut self) -> String {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            let n = time::timeout(REPLY_TIMEOUT, self.stream.read_line(&mut line))
+                .await
+                .expect("no reply from server")
+                .unwrap();
+            assert!(n > 0, "connection closed, got {reply:?}");
+            reply.push_str(&line);
+
+            // Only the last line has a space right after the code.
+            let line = line.as_bytes();
+            if line.len() > 3 && line[..3].iter().all(u8::is_ascii_digit) && line[3] == b' ' {
+                return reply.trim_end().to_string();
+            }
+        }
+    }
+
+    async fn expect(&mut self, code: u16) -> String {
+        let reply = self.reply().await;
+        assert!(
+            reply.starts_with(&code.to_string()),
+            "expected {code}, got {reply:?}"
+        );
+        reply
+    }
+
+    /// Reads the `125`/`150` reply a transfer starts with.
+    async fn expect_preliminary(&mut self) -> String {
+        let reply = self.reply().await;
+        assert!(
+            reply.starts_with("125") || reply.starts_with("150"),
+            "expected a preliminary reply, got {reply:?}"
+        );
+        reply
+    }
+
+    async fn cmd(&mut self, line: &str, code: u16) -> String {
+        self.send(line).await;
+        self.expect(code).await
+    }
+
+    async fn login(&mut self, username: &str) {
+        self.cmd(&format!("USER {username}"), 331).await;
+        self.cmd("PASS secret", 230).await;
+    }
+
+    /// Sets up passive mode and connects to the listener.
+    async fn passive(&mut self) -> TcpStream {
+        let reply = self.cmd("EPSV", 229).await;
+        let port: u16 = reply.split('|').nth(3).unwrap().parse().unwrap();
+        TcpStream::connect(("127.0.0.1", port)).await.unwrap()
+    }
+
+    /// Sets up active mode with a listener the server connects to.
+    async fn active(&mut self) -> TcpListener {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        self.cmd(
+            &format!("PORT 127,0,0,1,{},{}", port / 256, port % 256),
+            200,
+        )
+        .await;
+        listener
+    }
+
+    async fn retrieve(&mut self, path: &str) -> Vec<u8> {
+        let mut data = self.passive().await;
+        self.send(&format!("RETR {path}")).await;
+        self.expect_preliminary().await;
+        let mut content = Vec::new();
+        data.read_to_end(&mut content).await.unwrap();
+        self.expect(226).await;
+        content
+    }
+
+    /// Uploads a file and returns the final reply.
+    async fn store(&mut self, path: &str, content: &[u8]) -> String {
+        let mut data = self.passive().await;
+        self.send(&format!("STOR {path}")).await;
+        let reply = self.reply().await;
+        if !reply.starts_with('1') {
+            return reply;
+        }
+        data.write_all(content).await.unwrap();
+        drop(data);
+        self.reply().await
+    }
+
+    async fn list(&mut self, command: &str) -> String {
+        let mut data = self.passive().await;
+        self.send(command).await;
+        self.expect_preliminary().await;
+        let mut listing = String::new();
+        data.read_to_string(&mut listing).await.unwrap();
+        self.expect(226).await;
+        listing
+    }
+
+    /// Waits for the session to end and returns why it did.
+    async fn finished(self) -> Result<(), ConnectionError> {
+        time::timeout(REPLY_TIMEOUT, self.session)
+            .await
+            .expect("session is still running")
+            .unwrap()
+    }
+}
+
+fn root_with(files: &[(&str, &[u8])]) -> TempDir {
+    let root = tempfile::tempdir().unwrap();
+    for (name, content) in files {
+        fs::write(root.path().join(name), content).unwrap();
+    }
+    root
+}
+
+fn numbered(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[tokio::test]
+async fn session_runs_over_duplex_stream() {
+    let root = tempfile::tempdir().unwrap();
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    client.cmd("QUIT", 221).await;
+    assert_eq!(client.finished().await, Err(ConnectionError::ClosedByQuit));
+}
+
+#[test]
+fn strips_telnet_commands() {
+    // IAC IP IAC DM, as sent before ABOR.
+    assert_eq!(
+        strip_telnet_commands(b"\xff\xf4\xff\xf2ABOR"),
+        b"ABOR".to_vec()
+    );
+    assert_eq!(strip_telnet_commands(b"a\xff\xffb"), b"a\xffb".to_vec());
+    // IAC WILL ECHO.
+    assert_eq!(strip_telnet_commands(b"\xff\xfb\x01NOOP"), b"NOOP".to_vec());
+    // IAC SB ... IAC SE.
+    assert_eq!(
+        strip_telnet_commands(b"\xff\xfa\x18\x01\xff\xf0SYST"),
+        b"SYST".to_vec()
+    );
+    assert_eq!(strip_telnet_commands(b"PWD\xff"), b"PWD".to_vec());
+}
+
+#[test]
+fn normalizes_paths() {
+    assert_eq!(
+        normalize_path(Path::new("//foo///bar")),
+        Path::new("/foo/bar")
+    );
+    assert_eq!(normalize_path(Path::new("/a/./b/.")), Path::new("/a/b"));
+    assert_eq!(normalize_path(Path::new("/a/../../b")), Path::new("/b"));
+    assert_eq!(normalize_path(Path::new("/../..")), Path::new("/"));
+    assert_eq!(
+        normalize_path(Path::new("relative/dir")),
+        Path::new("/relative/dir")
+    );
+}
+
+#[test]
+fn parses_and_formats_mdtm() {
+    let time = parse_mdtm("20240229123456").unwrap();
+    assert_eq!(format_mdtm(time), "20240229123456");
+    assert_eq!(parse_mdtm("20240229123456.789"), Some(time));
+    assert_eq!(format_mdtm(UNIX_EPOCH), "19700101000000");
+    assert_eq!(
+        parse_mdtm("20010909014640"),
+        Some(UNIX_EPOCH + Duration::from_secs(1_000_000_000))
+    );
+
+    for invalid in [
+        "2024022912345",
+        "20241329123456",
+        "20240231123456",
+        "20230229123456",
+        "20240229243456",
+        "2024o229123456",
+    ] {
+        assert_eq!(parse_mdtm(invalid), None, "{invalid}");
+    }
+}
+
+#[test]
+fn formats_listing_timestamps() {
+    let old = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    assert_eq!(format_timestamp(old, ListingTimezone::Utc), "Sep  9  2001");
+
+    // Files from the last six months show time instead of year.
+    let recent = SystemTime::now() - Duration::from_secs(3600);
+    let formatted = format_timestamp(recent, ListingTimezone::Utc);
+    assert_eq!(formatted.len(), 12, "{formatted}");
+    assert_eq!(&formatted[9..10], ":", "{formatted}");
+
+    let future = SystemTime::now() + Duration::from_secs(86_400 * 400);
+    assert!(!format_timestamp(future, ListingTimezone::Utc).contains(':'));
+}
+
+#[test]
+fn formats_listing_timestamps_in_local_time() {
+    let secs = 1_000_000_000;
+    let time = UNIX_EPOCH + Duration::from_secs(secs);
+    let offset = local_utc_offset(secs as i64);
+    let shifted = UNIX_EPOCH + Duration::from_secs((secs as i64 + offset) as u64);
+    assert_eq!(
+        format_timestamp(time, ListingTimezone::Local),
+        format_timestamp(shifted, ListingTimezone::Utc)
+    );
+}
+
+#[test]
+fn quotes_paths() {
+    assert_eq!(quote_path("/plain"), "\"/plain\"");
+    assert_eq!(quote_path("/my\"dir"), "\"/my\"\"dir\"");
+}
+
+#[tokio::test]
+async fn default_config_accepts_commands() {
+    let root = tempfile::tempdir().unwrap();
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    // Settings are replaced after the builder validated them, as by code constructing `Config` directly.
+    let mut client = Client::connect(
+        config(root.path()),
+        client,
+        server,
+        Arc::new(NoopObserver),
+        |mut s| {
+            s.config = Config {
+                root: root.path().to_string_lossy().to_string(),
+                ..Config::default()
+            };
+            s
+        },
+    )
+    .await;
+    client.cmd("SYST", 215).await;
+}
+
+#[tokio::test]
+async fn rest_applies_only_to_next_retrieve() {
+    let first = numbered(200);
+    let second = numbered(150);
+    let root = root_with(&[("first", &first), ("second", &second)]);
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    client.cmd("REST 100", 350).await;
+    assert_eq!(client.retrieve("first").await, first[100..]);
+    assert_eq!(client.retrieve("second").await, second);
+}
+
+#[tokio::test]
+async fn active_mode_retrieve_resumes_with_configured_reply() {
+    let content = numbered(200);
+    let root = root_with(&[("file", &content)]);
+    let mut client = Client::start(config(root.path()).configure(|c| {
+        c.messages.insert(
+            Message::RestartingDownload,
+            String::from("Fortsetzen ab {offset}."),
+        );
+    }))
+    .await;
+    client.login("alice").await;
+
+    let listener = client.active().await;
+    client.cmd("REST 120", 350).await;
+    client.send("RETR file").await;
+    let (mut data, _) = listener.accept().await.unwrap();
+    let reply = client.expect_preliminary().await;
+    assert!(reply.ends_with("Fortsetzen ab 120."), "{reply:?}");
+
+    let mut received = Vec::new();
+    data.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, content[120..]);
+    client.expect(226).await;
+}
+
+#[tokio::test]
+async fn syst_uses_configured_system_type() {
+    let root = tempfile::tempdir().unwrap();
+    let mut client = Client::start(
+        config(root.path()).configure(|c| c.system_type = Some(String::from("Windows_NT"))),
+    )
+    .await;
+    assert_eq!(client.cmd("SYST", 215).await, "215 Windows_NT");
+}
+
+#[tokio::test]
+async fn configured_texts_replace_replies() {
+    let root = tempfile::tempdir().unwrap();
+    let messages = serde_json::json!({ "login_success": "Willkommen!", "system_type": "MACOS" });
+    let builder = config(root.path())
+        .banner("Dock\nPrivate server")
+        .configure(|c| c.messages = serde_json::from_value(messages).unwrap());
+    let mut client = Client::start(builder).await;
+    assert_eq!(client.greeting, "220-Dock\r\n220 Private server");
+
+    client.cmd("USER alice", 331).await;
+    assert_eq!(client.cmd("PASS secret", 230).await, "230 Willkommen!");
+    assert_eq!(client.cmd("SYST", 215).await, "215 MACOS");
+    // Replies without an override keep their default text.
+    let reply = client.cmd("CWD missing", 550).await;
+    assert!(reply.ends_with(Message::PathNotFound.default_text()));
+}
+
+#[tokio::test]
+async fn mlsd_uses_facts_selected_with_opts() {
+    let root = root_with(&[("file", b"content")]);
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    let reply = client.cmd("OPTS MLST type;SIZE;bogus;", 200).await;
+    assert_eq!(reply, "200 MLST OPTS type;size;");
+    // Selection applies to every listing that follows, not only the next one.
+    for _ in 0..2 {
+        assert_eq!(client.list("MLSD").await, "type=file;size=7; file\r\n");
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn mlst_reports_numeric_owner_ids() {
+    use std::os::unix::fs::MetadataExt;
+
+    let root = root_with(&[("file", b"content")]);
+    let metadata = fs::metadata(root.path().join("file")).unwrap();
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    let features = client.cmd("FEAT", 211).await;
+    assert!(features.contains("UNIX.uid*;UNIX.gid*;"), "{features:?}");
+    client.cmd("OPTS MLST type;unix.uid;UNIX.gid", 200).await;
+    let reply = client.cmd("MLST /file", 250).await;
+    let expected = format!(
+        " type=file;UNIX.uid={};UNIX.gid={}; /file",
+        metadata.uid(),
+        metadata.gid()
+    );
+    assert!(reply.lines().any(|l| l == expected), "{reply:?}");
+}
+
+#[tokio::test]
+async fn mfmt_sets_modification_time() {
+    let root = root_with(&[("file", b"data")]);
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    let reply = client.cmd("MFMT 20240229123456 file", 213).await;
+    assert_eq!(reply, "213 Modify=20240229123456; file");
+    let modified = fs::metadata(root.path().join("file"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    assert_eq!(modified, parse_mdtm("20240229123456").unwrap());
+    assert_eq!(client.cmd("MDTM file", 213).await, "213 20240229123456");
+
+    let reply = client.cmd("MFMT 20240231123456 file", 501).await;
+    assert!(reply.ends_with(Message::InvalidTimestamp.default_text()));
+    let reply = client.cmd("MFMT 20240229123456 missing", 550).await;
+    assert!(reply.ends_with(Message::FileUnavailable.default_text()));
+    assert_eq!(
+        fs::metadata(root.path().join("file"))
+            .unwrap()
+            .modified()
+            .unwrap(),
+        modified
+    );
+}
+
+#[tokio::test]
+async fn read_only_mode_blocks_uploads() {
+    let root = tempfile::tempdir().unwrap();
+    let mut client = Client::start(config(root.path()).read_only(true)).await;
+    client.login("alice").await;
+
+    client.cmd("EPSV", 229).await;
+    let reply = client.cmd("STOR new.txt", 550).await;
+    assert!(reply.ends_with(Message::ServerReadOnly.default_text()));
+    assert!(!root.path().join("new.txt").exists());
+}
+
+#[tokio::test]
+async fn reinitialize_logs_out() {
+    let root = tempfile::tempdir().unwrap();
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    client.cmd("REIN", 220).await;
+    client.cmd("LIST", 530).await;
+}
+
+#[tokio::test]
+async fn port_to_foreign_address_is_rejected() {
+    let root = tempfile::tempdir().unwrap();
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    let reply = client.cmd("PORT 192,0,2,1,4,1", 501).await;
+    assert!(reply.ends_with(Message::IllegalPortAddress.default_text()));
+    client.cmd("EPRT |1|192.0.2.1|1025|", 501).await;
+}
+
+#[tokio::test]
+async fn verbs_are_case_insensitive() {
+    let root = tempfile::tempdir().unwrap();
+    let mut client = Client::start(config(root.path())).await;
+
+    client.cmd("user alice", 331).await;
+    client.cmd("Pass secret", 230).await;
+    assert!(client.cmd("pwd", 257).await.starts_with("257 \"/\""));
+    client.cmd("sYsT", 215).await;
+}
+
+#[tokio::test]
+async fn opts_utf8_switches_name_decoding() {
+    let root = root_with(&[("café", b"data")]);
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    let reply = client.cmd("OPTS UTF8 OFF", 200).await;
+    assert!(reply.ends_with(Message::Utf8Disabled.default_text()));
+    assert!(client.cmd("STAT", 211).await.contains("UTF-8: off"));
+    // Without UTF-8 the name arrives as Latin-1.
+    client.send_raw(b"SIZE caf\xe9\r\n").await;
+    assert_eq!(client.expect(213).await, "213 4");
+
+    let reply = client.cmd("opts utf8 on", 200).await;
+    assert!(reply.ends_with(Message::Utf8Enabled.default_text()));
+    assert!(client.cmd("STAT", 211).await.contains("UTF-8: on"));
+    assert_eq!(client.cmd("SIZE café", 213).await, "213 4");
+    client.cmd("OPTS UTF8 MAYBE", 501).await;
+}
+
+#[tokio::test]
+async fn mode_accepts_only_stream() {
+    let root = tempfile::tempdir().unwrap();
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    assert_eq!(client.cmd("MODE S", 200).await, "200 Mode set to S.");
+    assert_eq!(client.cmd("mode s", 200).await, "200 Mode set to S.");
+    let reply = client.cmd("MODE B", 504).await;
+    assert!(reply.ends_with(Message::UnsupportedMode.default_text()));
+    let reply = client.cmd("MODE X", 501).await;
+    assert!(reply.ends_with(Message::UnknownMode.default_text()));
+    client.cmd("MODE", 501).await;
+    assert!(client.cmd("STAT", 211).await.contains("MODE: S"));
+}
+
+#[tokio::test]
+async fn stru_accepts_only_file_structure() {
+    let root = tempfile::tempdir().unwrap();
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    assert_eq!(client.cmd("STRU F", 200).await, "200 Structure set to F.");
+    assert_eq!(client.cmd("stru f", 200).await, "200 Structure set to F.");
+    let reply = client.cmd("STRU R", 504).await;
+    assert!(reply.ends_with(Message::UnsupportedStructure.default_text()));
+    let reply = client.cmd("STRU X", 501).await;
+    assert!(reply.ends_with(Message::UnknownStructure.default_text()));
+    client.cmd("STRU", 501).await;
+    assert!(client.cmd("STAT", 211).await.contains("STRU: F"));
+}
+
+#[tokio::test]
+async fn extra_spaces_after_verb_are_ignored() {
+    let root = tempfile::tempdir().unwrap();
+    fs::create_dir(root.path().join("two  spaces")).unwrap();
+    let mut client = Client::start(config(root.path())).await;
+
+    client.cmd("USER    alice", 331).await;
+    client.cmd("PASS  secret", 230).await;
+    client.cmd("SYST   ", 215).await;
+    // Spaces inside the argument belong to the name.
+    client.cmd("CWD   two  spaces", 250).await;
+    assert!(
+        client
+            .cmd("PWD", 257)
+            .await
+            .starts_with("257 \"/two  spaces\"")
+    );
+}
+
+#[tokio::test]
+async fn control_characters_in_file_names_are_rejected() {
+    let root = root_with(&[("a", b"data")]);
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    // A line feed always ends the command, so only NUL and a bare CR can reach a name.
+    for command in ["STOR", "RETR"] {
+        for name in [&b"a\0b"[..], b"a\rb", b"a\0"] {
+            client
+                .send_raw(&[command.as_bytes(), b" ", name, b"\r\n"].concat())
+                .await;
+            let reply = client.expect(553).await;
+            assert!(reply.ends_with(Message::FileNameNotAllowed.default_text()));
+        }
+    }
+    assert_eq!(fs::read_dir(root.path()).unwrap().count(), 1);
+}
+
+#[tokio::test]
+async fn blank_lines_get_no_reply() {
+    let root = tempfile::tempdir().unwrap();
+    let mut client = Client::start(config(root.path())).await;
+
+    client.send_raw(b"\r\n   \r\n").await;
+    client.cmd("SYST", 215).await;
+}
+
+#[tokio::test]
+async fn passive_only_rejects_active_mode() {
+    let root = tempfile::tempdir().unwrap();
+    let mut client = Client::start(config(root.path()).configure(|c| c.passive_only = true)).await;
+    client.login("alice").await;
+
+    let reply = client.cmd("PORT 127,0,0,1,4,1", 500).await;
+    assert!(reply.ends_with(Message::ActiveModeDisabled.default_text()));
+    client.cmd("EPRT |1|127.0.0.1|1025|", 500).await;
+}
+
+#[tokio::test]
+async fn active_only_rejects_passive_mode() {
+    let root = tempfile::tempdir().unwrap();
+    let mut client = Client::start(config(root.path()).configure(|c| c.active_only = true)).await;
+    client.login("alice").await;
+
+    let reply = client.cmd("PASV", 500).await;
+    assert!(reply.ends_with(Message::PassiveModeDisabled.default_text()));
+    client.cmd("EPSV", 500).await;
+}
+
+#[tokio::test]
+async fn login_over_ipv6() {
+    let root = tempfile::tempdir().unwrap();
+    let builder = Config::builder("[::1]:0", root.path().to_string_lossy()).user(User::new(
+        "alice",
+        "secret",
+        Permissions::All,
+    ));
+    let mut client = Client::start_tcp_on(builder, IpAddr::V6(Ipv6Addr::LOCALHOST)).await;
+    client.login("alice").await;
+    assert!(client.cmd("PWD", 257).await.starts_with("257 \"/\""));
+}
+
+#[tokio::test]
+async fn ipv6_clients_use_extended_passive_mode() {
+    let root = root_with(&[("file", b"data")]);
+    let mut client =
+        Client::start_tcp_on(config(root.path()), IpAddr::V6(Ipv6Addr::LOCALHOST)).await;
+    client.login("alice").await;
+
+    let reply = client.cmd("PASV", 425).await;
+    assert!(reply.ends_with(Message::UseEpsv.default_text()));
+
+    // The data listener is on the address the client has reached, so on IPv6 too.
+    let reply = client.cmd("EPSV", 229).await;
+    let port: u16 = reply.split('|').nth(3).unwrap().parse().unwrap();
+    let mut data = TcpStream::connect((Ipv6Addr::LOCALHOST, port))
+        .await
+        .unwrap();
+    client.send("RETR file").await;
+    client.expect_preliminary().await;
+    let mut content = Vec::new();
+    data.read_to_end(&mut content).await.unwrap();
+    assert_eq!(content, b"data");
+    client.expect(226).await;
+}
+
+#[tokio::test]
+async fn passive_listener_binds_to_data_address() {
+    let root = tempfile::tempdir().unwrap();
+    let data_address = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+    let mut client =
+        Client::start(config(root.path()).configure(|c| c.data_address = Some(data_address))).await;
+    client.login("alice").await;
+
+    let reply = client.cmd("PASV", 227).await;
+    assert!(reply.contains("(127,0,0,2,"), "{reply}");
+    let reply = client.cmd("EPSV", 229).await;
+    let port: u16 = reply.split('|').nth(3).unwrap().parse().unwrap();
+    assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    TcpStream::connect((data_address, port)).await.unwrap();
+}
+
+#[tokio::test]
+async fn unusable_data_address_keeps_session() {
+    let root = tempfile::tempdir().unwrap();
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    // Address isn't assigned to any interface, as if it was removed after the server started.
+    let mut client = Client::connect(
+        config(root.path()),
+        client,
+        server,
+        Arc::new(NoopObserver),
+        |mut s| {
+            s.config.data_address = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+            s
+        },
+    )
+    .await;
+    client.login("alice").await;
+
+    let reply = client.cmd("PASV", 425).await;
+    assert!(reply.ends_with(Message::CantOpenDataConnection.default_text()));
+    client.cmd("EPSV", 425).await;
+    client.cmd("SYST", 215).await;
+}
+
+#[tokio::test]
+async fn transfers_require_data_channel() {
+    let root = root_with(&[("file", b"data")]);
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    for command in ["RETR file", "STOR other", "LIST", "MLSD"] {
+        let reply = client.cmd(command, 425).await;
+        assert!(reply.ends_with(Message::DataChannelRequired.default_text()));
+    }
+    client.cmd("SYST", 215).await;
+}
+
+#[tokio::test]
+async fn pipelined_transfer_is_refused() {
+    let first = numbered(100);
+    let root = root_with(&[("first", &first), ("second", b"second")]);
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    let mut data = client.passive().await;
+    client
+        .send_raw(b"RETR first\r\nRETR second\r\nSYST\r\n")
+        .await;
+    client.expect_preliminary().await;
+    let mut content = Vec::new();
+    data.read_to_end(&mut content).await.unwrap();
+    assert_eq!(content, first);
+    client.expect(226).await;
+
+    let reply = client.expect(503).await;
+    assert!(reply.ends_with(Message::BadSequence.default_text()));
+    // Commands other than transfers are still handled in order.
+    client.expect(215).await;
+    assert_eq!(client.retrieve("second").await, b"second");
+}
+
+#[tokio::test]
+async fn listing_filters_by_pattern() {
+    let root = root_with(&[
+        ("a.txt", b""),
+        ("b.txt", b""),
+        ("c.log", b""),
+        ("ab.txt", b""),
+    ]);
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    let names = |listing: String| {
+        let mut names: Vec<String> = listing
+            .lines()
+            .map(|l| l.rsplit(' ').next().unwrap().to_string())
+            .collect();
+        names.sort();
+        names
+    };
+    assert_eq!(
+        names(client.list("LIST *.txt").await),
+        ["a.txt", "ab.txt", "b.txt"]
+    );
+    assert_eq!(names(client.list("LIST ?.txt").await), ["a.txt", "b.txt"]);
+    assert_eq!(names(client.list("LIST /[!a]*").await), ["b.txt", "c.log"]);
+}
+
+#[tokio::test]
+async fn write_only_user_cannot_browse() {
+    let root = root_with(&[("file", b"data")]);
+    let mut client = Client::start(config(root.path())).await;
+    client.login("writer").await;
+
+    for command in ["LIST", "SIZE file", "MDTM file", "RETR file"] {
+        let reply = client.cmd(command, 550).await;
+        assert!(reply.ends_with(Message::NoReadPermission.default_text()));
+    }
+}
+
+#[tokio::test]
+async fn no_overwrite_keeps_existing_file() {
+    let root = root_with(&[("file", b"original")]);
+    let mut client = Client::start(config(root.path()).configure(|c| c.no_overwrite = true)).await;
+    client.login("alice").await;
+
+    client.cmd("EPSV", 229).await;
+    let reply = client.cmd("STOR file", 553).await;
+    assert!(reply.ends_with(Message::FileExists.default_text()));
+    assert_eq!(fs::read(root.path().join("file")).unwrap(), b"original");
+
+    assert!(client.store("new", b"fresh").await.starts_with("226"));
+}
+
+#[tokio::test]
+async fn rest_before_listing_is_rejected() {
+    let content = numbered(200);
+    let root = root_with(&[("file", &content)]);
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    client.cmd("REST 100", 350).await;
+    client.cmd("LIST", 554).await;
+    assert_eq!(client.retrieve("file").await, content);
+
+    client.cmd("REST 100", 350).await;
+    client.cmd("SYST", 215).await;
+    assert_eq!(client.retrieve("file").await, content);
+}
+
+#[tokio::test]
+async fn garbage_input_closes_session() {
+    let root = tempfile::tempdir().unwrap();
+    let mut client = Client::start(config(root.path())).await;
+
+    // Deterministic noise, one line of it per command.
+    let mut state = 0x2545_f491_u32;
+    let mut line = || {
+        let mut bytes: Vec<u8> = (0..40)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 24) as u8
+            })
+            .filter(|b| !b.is_ascii_whitespace())
+            .collect();
+        bytes.insert(0, 0x80);
+        bytes.extend_from_slice(b"\r\n");
+        bytes
+    };
+
+    for _ in 0..MAX_UNRECOGNIZED_COMMANDS - 1 {
+        client.send_raw(&line()).await;
+        client.expect(500).await;
+    }
+    client.send_raw(&line()).await;
+    client.expect(500).await;
+    client.expect(421).await;
+    assert_eq!(
+        client.finished().await,
+        Err(ConnectionError::TooManyUnrecognizedCommands)
+    );
+}
+
+#[tokio::test]
+async fn login_starts_in_configured_directory() {
+    let root = tempfile::tempdir().unwrap();
+    fs::create_dir_all(root.path().join("home/alice")).unwrap();
+    let mut alice = User::new("alice", "secret", Permissions::All);
+    alice.start_dir = Some(String::from("home/alice"));
+    let mut bob = User::new("bob", "secret", Permissions::All);
+    bob.start_dir = Some(String::from("missing"));
+    let builder = Config::builder("127.0.0.1:0", root.path().to_string_lossy())
+        .user(alice)
+        .user(bob);
+    let mut client = Client::start(builder).await;
+
+    client.login("alice").await;
+    assert!(
+        client
+            .cmd("PWD", 257)
+            .await
+            .starts_with("257 \"/home/alice\"")
+    );
+    // A missing directory falls back to root.
+    client.login("bob").await;
+    assert!(client.cmd("PWD", 257).await.starts_with("257 \"/\""));
+}
+
+#[tokio::test]
+async fn user_switches_account() {
+    let root = tempfile::tempdir().unwrap();
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    client.cmd("USER bob", 331).await;
+    client.cmd("LIST", 530).await;
+    client.cmd("PASS secret", 230).await;
+    let status = client.cmd("STAT", 211).await;
+    assert!(status.contains("Logged in as bob"), "{status}");
+}
+
+#[tokio::test]
+async fn legacy_directory_aliases() {
+    let root = tempfile::tempdir().unwrap();
+    fs::create_dir_all(root.path().join("a/b")).unwrap();
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    client.cmd("XCWD a/b", 250).await;
+    assert!(client.cmd("XPWD", 257).await.starts_with("257 \"/a/b\""));
+    client.cmd("XCUP", 250).await;
+    assert!(client.cmd("XPWD", 257).await.starts_with("257 \"/a\""));
+    // XMKD and XRMD are absent along with MKD and RMD.
+    client.cmd("XMKD c", 500).await;
+}
+
+#[tokio::test]
+async fn working_directory_quotes_are_doubled() {
+    let root = tempfile::tempdir().unwrap();
+    fs::create_dir(root.path().join("my\"dir")).unwrap();
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    client.cmd("CWD my\"dir", 250).await;
+    let reply = client.cmd("PWD", 257).await;
+    assert!(reply.starts_with("257 \"/my\"\"dir\" "), "{reply}");
+}
+
+#[tokio::test]
+async fn ascii_transfers_translate_line_endings() {
+    let root = tempfile::tempdir().unwrap();
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    client.cmd("TYPE A", 200).await;
+    let reply = client.store("text", b"one\r\ntwo\r\n").await;
+    assert!(reply.starts_with("226"), "{reply}");
+    assert_eq!(fs::read(root.path().join("text")).unwrap(), b"one\ntwo\n");
+    assert_eq!(client.retrieve("text").await, b"one\r\ntwo\r\n");
+
+    client.cmd("TYPE I", 200).await;
+    assert_eq!(client.retrieve("text").await, b"one\ntwo\n");
+    client.cmd("TYPE X", 504).await;
+}
+
+#[tokio::test]
+async fn listing_shows_symlink_targets() {
+    let root = root_with(&[("target", b"data")]);
+    std::os::unix::fs::symlink("target", root.path().join("relative")).unwrap();
+    std::os::unix::fs::symlink(root.path().join("target"), root.path().join("absolute")).unwrap();
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    let listing = client.list("LIST").await;
+    let line = |name: &str| {
+        listing
+            .lines()
+            .find(|l| l.split_whitespace().nth(8) == Some(name))
+            .unwrap_or_else(|| panic!("{name} not in {listing:?}"))
+            .to_string()
+    };
+    let relative = line("relative");
+    assert!(relative.starts_with('l'), "{relative}");
+    assert!(relative.ends_with(" relative -> target"), "{relative}");
+    // Targets inside root are shown as virtual paths.
+    let absolute = line("absolute");
+    assert!(absolute.starts_with('l'), "{absolute}");
+    assert!(absolute.ends_with(" absolute -> /target"), "{absolute}");
+    assert!(line("target").starts_with('-'));
+}
+
+#[tokio::test]
+async fn large_directories_are_listed_in_full() {
+    let root = tempfile::tempdir().unwrap();
+    for i in 0..3000 {
+        fs::write(root.path().join(format!("file{i:04}")), b"").unwrap();
+    }
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    for command in ["LIST", "MLSD"] {
+        let listing = client.list(command).await;
+        let mut names: Vec<&str> = listing
+            .lines()
+            .map(|l| l.rsplit(' ').next().unwrap())
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), 3000, "{command}");
+        assert_eq!(names[0], "file0000");
+        assert_eq!(names[2999], "file2999");
+    }
+}
+
+#[tokio::test]
+async fn listing_survives_closed_data_connection() {
+    let root = tempfile::tempdir().unwrap();
+    for i in 0..500 {
+        fs::write(root.path().join(format!("file-{i:04}")), b"").unwrap();
+    }
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    // Resetting the connection makes the server's writes fail instead of filling socket buffers.
+    let data = client.passive().await;
+    data.set_linger(Some(Duration::ZERO)).unwrap();
+    drop(data);
+
+    client.send("LIST").await;
+    client.expect_preliminary().await;
+    let reply = client.expect(426).await;
+    assert!(reply.ends_with(Message::TransferAborted.default_text()));
+    client.cmd("SYST", 215).await;
+}
+
+/// Observer recording progress events.
+#[derive(Default)]
+struct ProgressRecorder {
+    events: Mutex<Vec<(u64, Option<u64>)>>,
+}
+
+#[async_trait::async_trait]
+impl SessionObserver for ProgressRecorder {
+    fn progress_interval(&self) -> Option<Duration> {
+        Some(Duration::ZERO)
+    }
+
+    async fn on_transfer_progress(
+        &self,
+        _session_id: &str,
+        _path: &Path,
+        bytes: u64,
+        total: Option<u64>,
+    ) {
+        self.events.lock().unwrap().push((bytes, total));
+    }
+}
+
+#[tokio::test]
+async fn reports_transfer_progress() {
+    let content = numbered(10 * 1024);
+    let root = root_with(&[("file", &content)]);
+    let recorder = Arc::new(ProgressRecorder::default());
+    let builder = config(root.path()).configure(|c| c.transfer_buffer_size = 1024);
+    let mut client = Client::start_with(builder, recorder.clone()).await;
+    client.login("alice").await;
+
+    assert_eq!(client.retrieve("file").await, content);
+    let events = recorder.events.lock().unwrap().clone();
+    assert_eq!(events.len(), 10);
+    assert!(events.iter().all(|(_, total)| *total == Some(10 * 1024)));
+    assert!(events.windows(2).all(|w| w[0].0 < w[1].0));
+    assert_eq!(events.last().unwrap().0, 10 * 1024);
+}
+
+/// Log output shared with a test, as `tracing` only writes to `io::Write` sinks.
+#[derive(Clone, Default)]
+struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn passwords_are_not_logged() {
+    let logs = LogBuffer::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_writer(move || writer.clone())
+        .finish();
+    // Sessions of this test run on its thread, so a thread local subscriber sees all of them.
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let root = tempfile::tempdir().unwrap();
+    let builder = Config::builder("127.0.0.1:0", root.path().to_string_lossy()).user(User::new(
+        "alice",
+        "correct horse",
+        Permissions::All,
+    ));
+    let mut client = Client::start(builder).await;
+    client.cmd("USER alice", 331).await;
+    client.cmd("PASS correct horse", 230).await;
+    client.cmd("ACCT billing", 230).await;
+
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    assert!(logs.contains("Received command."), "{logs}");
+    assert!(!logs.contains("correct horse"), "{logs}");
+    assert!(!logs.contains("billing"), "{logs}");
+}
+
+#[tokio::test]
+async fn removed_root_is_reported() {
+    let logs = LogBuffer::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let root = root_with(&[("file", b"data")]);
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+    client.cmd("SIZE file", 213).await;
+
+    fs::remove_dir_all(root.path()).unwrap();
+    let reply = client.cmd("SIZE file", 550).await;
+    assert!(reply.ends_with(Message::RootUnavailable.default_text()));
+    // The session stays, in case the root comes back.
+    client.cmd("SYST", 215).await;
+
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    assert!(
+        logs.contains("Served root directory is unavailable."),
+        "{logs}"
+    );
+}
+
+#[tokio::test]
+async fn clnt_is_accepted_before_login() {
+    let logs = LogBuffer::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let root = tempfile::tempdir().unwrap();
+    let mut client = Client::start(config(root.path())).await;
+
+    let reply = client.cmd("CLNT", 501).await;
+    assert!(reply.ends_with(Message::ClientNameRequired.default_text()));
+    let reply = client.cmd("CLNT FileZilla 3.66", 200).await;
+    assert!(reply.ends_with(Message::Noted.default_text()));
+    client.login("alice").await;
+
+    // The name is logged with the login.
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    let line = logs
+        .lines()
+        .find(|l| l.contains("User authorized."))
+        .unwrap();
+    assert!(line.contains("client=FileZilla 3.66"), "{line}");
+}
+
+/// Starts an upload and resets the data connection after sending part of the file.
+async fn interrupted_store(client: &mut Client, path: &str) {
+    let mut data = client.passive().await;
+    client.send(&format!("STOR {path}")).await;
+    client.expect_preliminary().await;
+    data.write_all(b"partial").await.unwrap();
+    data.set_linger(Some(Duration::ZERO)).unwrap();
+    drop(data);
+    let reply = client.expect(426).await;
+    assert!(reply.ends_with(Message::TransferAborted.default_text()));
+}
+
+#[tokio::test]
+async fn failed_upload_removes_partial_file() {
+    let root = root_with(&[("existing", b"original")]);
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    interrupted_store(&mut client, "new").await;
+    assert!(!root.path().join("new").exists());
+
+    // A failed replacement keeps the original file and leaves nothing else behind.
+    interrupted_store(&mut client, "existing").await;
+    assert_eq!(fs::read(root.path().join("existing")).unwrap(), b"original");
+    assert_eq!(fs::read_dir(root.path()).unwrap().count(), 1);
+}
+
+#[tokio::test]
+async fn replaced_file_keeps_its_mode() {
+    let root = root_with(&[("existing", b"original")]);
+    let path = root.path().join("existing");
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    assert!(
+        client
+            .store("existing", b"replaced")
+            .await
+            .starts_with("226")
+    );
+    assert_eq!(fs::read(&path).unwrap(), b"replaced");
+    assert_eq!(
+        fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+        0o600
+    );
+}
+
+#[tokio::test]
+async fn failed_resumed_upload_keeps_file() {
+    let root = root_with(&[("a.txt", b"12345")]);
+    let builder = config(root.path()).configure(|c| c.max_upload_size = 1024);
+    let mut client = Client::start(builder).await;
+    client.login("alice").await;
+
+    client.cmd("REST 2", 350).await;
+    interrupted_store(&mut client, "a.txt").await;
+    let content = fs::read(root.path().join("a.txt")).unwrap();
+    assert!(content.starts_with(b"12"), "{content:?}");
+}
+
+#[tokio::test]
+async fn upload_without_data_connection_removes_file() {
+    let root = tempfile::tempdir().unwrap();
+    let mut client = Client::start(config(root.path())).await;
+    client.login("alice").await;
+
+    // Nothing listens on the port once the listener is dropped, so the server can't connect.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    drop(listener);
+    client
+        .cmd(
+            &format!("PORT 127,0,0,1,{},{}", port / 256, port % 256),
+            200,
+        )
+        .await;
+
+    client.send("STOR new").await;
+    client.expect_preliminary().await;
+    client.expect(425).await;
+    assert!(!root.path().join("new").exists());
+}
+
+#[tokio::test]
+async fn greeting_is_sent_before_client_speaks() {
+    let root = tempfile::tempdir().unwrap();
+    // Starting a client only reads, so the greeting must not wait for anything from it.
+    let client = Client::start_tcp(config(root.path())).await;
+    assert_eq!(client.greeting, "220 Dock is welcoming you!");
+
+    let client = Client::start_tcp(config(root.path()).banner("One\nTwo")).await;
+    assert_eq!(client.greeting, "220-One\r\n220 Two");
+}
+
+#[tokio::test]
+async fn buffered_commands_are_handled_after_client_closes() {
+    let root = tempfile::tempdir().unwrap();
+    let mut client = Client::start_tcp(config(root.path())).await;
+
+    client
+        .send_raw(b"USER alice\r\nPASS secret\r\nPWD\r\nQUIT\r\n")
+        .await;
+    client.stream.shutdown().await.unwrap();
+    client.expect(331).await;
+    client.expect(230).await;
+    client.expect(257).await;
+    client.expect(221).await;
+    assert_eq!(client.finished().await, Err(ConnectionError::ClosedByQuit));
+}
+
+/// Sends data with the TCP urgent flag, as tokio has no API for it.
+#[cfg(unix)]
+fn send_urgent(stream: &TcpStream, data: &[u8]) {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the descriptor is open and `data` is valid for reads of its length.
+    let sent = unsafe {
+        libc::send(
+            stream.as_raw_fd(),
+            data.as_ptr().cast(),
+            data.len(),
+            libc::MSG_OOB,
+        )
+    };
+    assert_eq!(sent, data.len() as isize);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn aborted_resumed_download_delivers_range() {
+    let content = numbered(1024 * 1024);
+    let root = root_with(&[("file", &content)]);
+    // Rate limit keeps the transfer running until it's aborted.
+    let mut user = User::new("alice", "secret", Permissions::All);
+    user.download_rate_limit = 256 * 1024;
+    let builder = Config::builder("127.0.0.1:0", root.path().to_string_lossy()).user(user);
+    let mut client = Client::start_tcp(builder).await;
+    client.login("alice").await;
+
+    let mut data = client.passive().await;
+    client.cmd("REST 1000", 350).await;
+    client.send("RETR file").await;
+    client.expect_preliminary().await;
+    let mut range = vec![0u8; 4096];
+    data.read_exact(&mut range).await.unwrap();
+    assert_eq!(range, content[1000..1000 + 4096]);
+
+    // Clients send ABOR after Telnet IP and synch, the latter as urgent data.
+    client.send_raw(b"\xff\xf4\xff").await;
+    send_urgent(client.stream.get_ref(), b"\xf2");
+    client.send_raw(b"ABOR\r\n").await;
+    client.expect(426).await;
+    let reply = client.expect(226).await;
+    assert!(reply.ends_with(Message::AbortSuccessful.default_text()));
+    drop(data);
+
+    // Session carries on, and the next download starts from the beginning.
+    assert_eq!(client.cmd("SIZE file", 213).await, "213 1048576");
+}
+
+#[tokio::test]
+async fn proxied_client_address_is_used() {
+    let root = tempfile::tempdir().unwrap();
+    let addr = "192.0.2.10:50000".parse().unwrap();
+    let mut client = Client::start_behind_proxy(config(root.path()), addr).await;
+    client.login("alice").await;
+
+    let status = client.cmd("STAT", 211).await;
+    assert!(status.contains("Connected to 192.0.2.10"), "{status}");
+    // The balancer's address is foreign to the client, its own is not.
+    client.cmd("PORT 127,0,0,1,4,1", 501).await;
+    client.cmd("PORT 192,0,2,10,4,1", 200).await;
+    client.cmd("EPRT |1|192.0.2.10|1025|", 200).await;
+}
+
+/// Disk backend that can't list one directory, as if the server had no permission to read it.
+/// Running tests as root would make real permissions ineffective.
+struct UnreadableDir {
+    disk: DiskFs,
+    path: PathBuf,
+}
+
+impl UnreadableDir {
+    fn check(&self, path: &Path) -> std::io::Result<()> {
+        if path == self.path {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl VirtualFs for UnreadableDir {
+    async fn read_dir(&self, path: &Path) -> std::io::Result<Vec<FileInfo>> {
+        self.check(path)?;
+        self.disk.read_dir(path).await
+    }
+
+    async fn open_dir(&self, path: &Path) -> std::io::Result<vfs::DirEntries> {
+        self.check(path)?;
+        self.disk.open_dir(path).await
+    }
+
+    async fn metadata(&self, path: &Path) -> std::io::Result<FileInfo> {
+        self.disk.metadata(path).await
+    }
+
+    async fn open(&self, path: &Path, offset: u64) -> std::io::Result<vfs::FileReader> {
+        self.disk.open(path, offset).await
+    }
+
+    async fn create(&self, path: &Path, offset: u64) -> std::io::Result<vfs::FileWriter> {
+        self.disk.create(path, offset).await
+    }
+
+    async fn create_new(&self, path: &Path) -> std::io::Result<vfs::FileWriter> {
+        self.disk.create_new(path).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        self.disk.rename(from, to).await
+    }
+
+    async fn remove(&self, path: &Path) -> std::io::Result<()> {
+        self.disk.remove(path).await
+    }
+}
+
+#[tokio::test]
+async fn change_dir_reports_why_it_failed() {
+    let root = root_with(&[("file", b"data")]);
+    fs::create_dir(root.path().join("open")).unwrap();
+    fs::create_dir(root.path().join("locked")).unwrap();
+    let vfs = Arc::new(UnreadableDir {
+        disk: DiskFs::new(root.path()),
+        path: PathBuf::from("/locked"),
+    });
+    let mut client = Client::start_with_vfs(config(root.path()), vfs).await;
+    client.login("alice").await;
+
+    for (path, message) in [
+        ("missing", Message::PathNotFound),
+        ("file", Message::NotADirectory),
+        ("locked", Message::PermissionDenied),
+    ] {
+        let reply = client.cmd(&format!("CWD {path}"), 550).await;
+        assert!(reply.ends_with(message.default_text()), "{path}: {reply}");
+    }
+    client.cmd("CWD open", 250).await;
+    assert!(client.cmd("PWD", 257).await.starts_with("257 \"/open\""));
+}
+
+/// Disk backend whose listings fail after the first entry, as if the disk went bad mid-listing.
+struct FailingListing {
+    disk: DiskFs,
+}
+
+/// Entries of a directory that can only produce the first one.
+struct FailingEntries {
+    entries: vfs::DirEntries,
+    failed: bool,
+}
+
+#[async_trait::async_trait]
+impl vfs::DirReader for FailingEntries {
+    async fn next_entry(&mut self) -> std::io::Result<Option<FileInfo>> {
+        if std::mem::replace(&mut self.failed, true) {
+            return Err(std::io::Error::other("read error"));
+        }
+        self.entries.next_entry().await
+    }
+}
+
+#[async_trait::async_trait]
+impl VirtualFs for FailingListing {
+    async fn read_dir(&self, path: &Path) -> std::io::Result<Vec<FileInfo>> {
+        self.disk.read_dir(path).await
+    }
+
+    async fn open_dir(&self, path: &Path) -> std::io::Result<vfs::DirEntries> {
+        Ok(Box::new(FailingEntries {
+            entries: self.disk.open_dir(path).await?,
+            failed: false,
+        }))
+    }
+
+    async fn metadata(&self, path: &Path) -> std::io::Result<FileInfo> {
+        self.disk.metadata(path).await
+    }
+
+    async fn open(&self, path: &Path, offset: u64) -> std::io::Result<vfs::FileReader> {
+        self.disk.open(path, offset).await
+    }
+
+    async fn create(&self, path: &Path, offset: u64) -> std::io::Result<vfs::FileWriter> {
+        self.disk.create(path, offset).await
+    }
+
+    async fn create_new(&self, path: &Path) -> std::io::Result<vfs::FileWriter> {
+        self.disk.create_new(path).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        self.disk.rename(from, to).await
+    }
+
+    async fn remove(&self, path: &Path) -> std::io::Result<()> {
+        self.disk.remove(path).await
+    }
+}
+
+#[tokio::test]
+async fn listing_error_keeps_session() {
+    let root = root_with(&[("a", b"1"), ("b", b"2")]);
+    let vfs = Arc::new(FailingListing {
+        disk: DiskFs::new(root.path()),
+    });
+    let mut client = Client::start_with_vfs(config(root.path()), vfs).await;
+    client.login("alice").await;
+
+    let mut data = client.passive().await;
+    client.send("LIST").await;
+    client.expect_preliminary().await;
+    let mut listing = String::new();
+    data.read_to_string(&mut listing).await.unwrap();
+    let reply = client.expect(451).await;
+    assert!(reply.ends_with(Message::LocalError.default_text()));
+
+    client.cmd("SYST", 215).await;
+    assert_eq!(client.retrieve("a").await, b"1");
+}
+
+#[tokio::test]
+async fn uploads_past_max_size_are_refused() {
+    let root = root_with(&[("a.txt", b"12345")]);
+    let builder = config(root.path()).configure(|c| c.max_upload_size = 8);
+    let mut client = Client::start(builder).await;
+    client.login("alice").await;
+
+    let reply = client.store("big", &[1u8; 20]).await;
+    assert!(reply.starts_with("552"), "{reply}");
+    assert!(reply.ends_with(Message::FileTooLarge.default_text()));
+    assert!(!root.path().join("big").exists());
+
+    // A resumed upload keeps the part it was resumed from.
+    client.cmd("REST 5", 350).await;
+    let reply = client.store("a.txt", &[1u8; 20]).await;
+    assert!(reply.starts_with("552"), "{reply}");
+    assert_eq!(fs::read(root.path().join("a.txt")).unwrap(), b"12345");
+}
+
+#[tokio::test]
+async fn uploads_past_quota_are_refused() {
+    let root = root_with(&[("existing", &[0u8; 30])]);
+    let mut user = User::new("alice", "secret", Permissions::All);
+    user.quota_bytes = 100;
+    let builder = Config::builder("127.0.0.1:0", root.path().to_string_lossy()).user(user);
+    let mut client = Client::start(builder).await;
+    client.login("alice").await;
+
+    assert!(client.store("first", &[1u8; 50]).await.starts_with("226"));
+    // Usage is counted once and then tracked, so the first upload must be accounted for.
+    let reply = client.store("second", &[2u8; 50]).await;
+    assert!(reply.starts_with("552"), "{reply}");
+    assert!(reply.ends_with(Message::StorageExceeded.default_text()));
+    assert!(!root.path().join("second").exists());
+
+    // Replacing a file only counts the difference.
+    assert!(client.store("first", &[1u8; 60]).await.starts_with("226"));
+    client.cmd("ALLO 10", 200).await;
+    client.cmd("ALLO 11", 552).await;
+}
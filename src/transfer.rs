@@ -0,0 +1,460 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ErrorKind},
+    time::{self, Instant},
+};
+
+use crate::observer::SessionObserver;
+
+/// Buffer size used when none is configured.
+pub const DEFAULT_BUFFER_SIZE: usize = 8192;
+
+/// Representation type set by the `TYPE` command.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransferType {
+    Ascii,
+    #[default]
+    Image,
+}
+
+impl TransferType {
+    /// Parses the argument of `TYPE`. Returns `None` for unsupported types.
+    pub fn parse(arg: &str) -> Option<Self> {
+        let mut parts = arg.split_whitespace();
+        let kind = parts.next()?.to_ascii_uppercase();
+        let param = parts.next().map(|p| p.to_ascii_uppercase());
+        match (kind.as_str(), param.as_deref()) {
+            ("A", None | Some("N")) => Some(TransferType::Ascii),
+            ("I", None) | ("L", Some("8")) => Some(TransferType::Image),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransferType::Ascii => "ASCII",
+            TransferType::Image => "BINARY",
+        }
+    }
+}
+
+/// Transmission mode set by the `MODE` command. Only stream mode is supported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransferMode {
+    #[default]
+    Stream,
+}
+
+impl TransferMode {
+    /// Parses the argument of `MODE`. Returns `None` for unsupported modes.
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg.trim().to_ascii_uppercase().as_str() {
+            "S" => Some(TransferMode::Stream),
+            _ => None,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransferMode::Stream => "S",
+        }
+    }
+}
+
+/// File structure set by the `STRU` command. Only file structure is supported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FileStructure {
+    #[default]
+    File,
+}
+
+impl FileStructure {
+    /// Parses the argument of `STRU`. Returns `None` for unsupported structures.
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg.trim().to_ascii_uppercase().as_str() {
+            "F" => Some(FileStructure::File),
+            _ => None,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            FileStructure::File => "F",
+        }
+    }
+}
+
+/// Line ending translation applied to a stream of chunks.
+enum Translation {
+    None,
+    /// Bare `LF` becomes `CRLF`.
+    ToCrlf {
+        previous: u8,
+    },
+    /// `CRLF` becomes `LF`. A `CR` at the end of a chunk is held back until we know whether `LF` follows it.
+    ToLf {
+        pending_cr: bool,
+    },
+}
+
+impl Translation {
+    fn translate(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        match self {
+            Translation::None => out.extend_from_slice(input),
+            Translation::ToCrlf { previous } => {
+                for &byte in input {
+                    if byte == b'\n' && *previous != b'\r' {
+                        out.push(b'\r');
+                    }
+                    out.push(byte);
+                    *previous = byte;
+                }
+            }
+            Translation::ToLf { pending_cr } => {
+                for &byte in input {
+                    if *pending_cr {
+                        *pending_cr = false;
+                        if byte != b'\n' {
+                            out.push(b'\r');
+                        }
+                    }
+
+                    if byte == b'\r' {
+                        *pending_cr = true;
+                    } else {
+                        out.push(byte);
+                    }
+                }
+            }
+        }
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        if let Translation::ToLf { pending_cr } = self
+            && *pending_cr
+        {
+            *pending_cr = false;
+            out.push(b'\r');
+        }
+    }
+}
+
+/// Keeps throughput under `rate` bytes per second. A rate of `0` means unlimited.
+struct Throttle {
+    rate: u64,
+    started: Instant,
+}
+
+impl Throttle {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            started: Instant::now(),
+        }
+    }
+
+    /// Limits chunks to what can be sent in a second, so pacing stays smooth.
+    fn chunk_size(&self, buffer_size: usize) -> usize {
+        if self.rate == 0 {
+            buffer_size
+        } else {
+            buffer_size.min(self.rate as usize)
+        }
+    }
+
+    async fn pace(&self, transferred: u64) {
+        if self.rate == 0 {
+            return;
+        }
+
+        let expected = Duration::from_secs_f64(transferred as f64 / self.rate as f64);
+        let elapsed = self.started.elapsed();
+        if expected > elapsed {
+            time::sleep(expected - elapsed).await;
+        }
+    }
+}
+
+/// Reports how far a transfer has got to an observer, at most once per interval.
+pub struct Progress {
+    observer: Arc<dyn SessionObserver>,
+    session_id: String,
+    path: PathBuf,
+    total: Option<u64>,
+    interval: Duration,
+    reported_at: Instant,
+}
+
+impl Progress {
+    /// Returns `None` if the observer doesn't want progress events, so they cost nothing.
+    pub fn new(
+        observer: Arc<dyn SessionObserver>,
+        session_id: &str,
+        path: PathBuf,
+        total: Option<u64>,
+    ) -> Option<Self> {
+        let interval = observer.progress_interval()?;
+        Some(Self {
+            observer,
+            session_id: session_id.to_owned(),
+            path,
+            total,
+            interval,
+            reported_at: Instant::now(),
+        })
+    }
+
+    async fn update(&mut self, bytes: u64) {
+        if self.reported_at.elapsed() < self.interval {
+            return;
+        }
+        self.reported_at = Instant::now();
+        self.observer
+            .on_transfer_progress(&self.session_id, &self.path, bytes, self.total)
+            .await;
+    }
+}
+
+/// Copies chunks from `reader` to `writer`, translating and pacing them.
+/// Fails with `FileTooLarge` before writing more than `max_written` bytes.
+/// Returns the number of bytes that went over the data connection.
+async fn copy<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    mut translation: Translation,
+    throttle: Throttle,
+    max_written: Option<u64>,
+    buffer_size: usize,
+    mut progress: Option<&mut Progress>,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let buffer_size = if buffer_size == 0 {
+        DEFAULT_BUFFER_SIZE
+    } else {
+        buffer_size
+    };
+    let mut buf = vec![0u8; throttle.chunk_size(buffer_size)];
+    let mut out = Vec::with_capacity(buf.len() * 2);
+    let mut total = 0u64;
+    let mut written = 0u64;
+    // Uploads are counted before conversion to `LF`, so sizes match what went over the connection.
+    let count_written = !matches!(translation, Translation::ToLf { .. });
+
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+
+        out.clear();
+        translation.translate(&buf[..n], &mut out);
+        written += out.len() as u64;
+        if max_written.is_some_and(|max| written > max) {
+            writer.flush().await?;
+            return Err(io::Error::new(
+                ErrorKind::FileTooLarge,
+                "upload limit exceeded",
+            ));
+        }
+        writer.write_all(&out).await?;
+
+        total += if count_written { out.len() } else { n } as u64;
+        if let Some(progress) = progress.as_deref_mut() {
+            progress.update(total).await;
+        }
+        throttle.pace(total).await;
+    }
+
+    out.clear();
+    translation.finish(&mut out);
+    writer.write_all(&out).await?;
+    writer.flush().await?;
+    Ok(total)
+}
+
+/// Sends file contents to the client with `sendfile`, so they don't pass through userspace buffers.
+/// Only suitable for binary transfers without rate limit.
+#[cfg(target_os = "linux")]
+pub async fn send_file(
+    file: &std::fs::File,
+    offset: u64,
+    socket: &tokio::net::TcpStream,
+) -> io::Result<u64> {
+    use std::os::fd::AsRawFd;
+    use tokio::io::Interest;
+
+    const SENDFILE_CHUNK: usize = 1 << 20;
+
+    let mut offset = libc::off_t::try_from(offset)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "offset is too large"))?;
+    let mut total = 0u64;
+    loop {
+        socket.writable().await?;
+        let sent = socket.try_io(Interest::WRITABLE, || {
+            // SAFETY: both descriptors stay open for the duration of the call.
+            let n = unsafe {
+                libc::sendfile(
+                    socket.as_raw_fd(),
+                    file.as_raw_fd(),
+                    &mut offset,
+                    SENDFILE_CHUNK,
+                )
+            };
+            if n < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(n as u64)
+            }
+        });
+
+        match sent {
+            Ok(0) => return Ok(total),
+            Ok(n) => total += n,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Copies file contents to the client. In ASCII mode bare `LF` line endings are sent as `CRLF`.
+pub async fn copy_to_client<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    transfer_type: TransferType,
+    rate_limit: u64,
+    buffer_size: usize,
+    progress: Option<&mut Progress>,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let translation = match transfer_type {
+        TransferType::Ascii => Translation::ToCrlf { previous: 0 },
+        TransferType::Image => Translation::None,
+    };
+    copy(
+        reader,
+        writer,
+        translation,
+        Throttle::new(rate_limit),
+        None,
+        buffer_size,
+        progress,
+    )
+    .await
+}
+
+/// Copies data received from the client into a file. In ASCII mode `CRLF` line endings are stored as `LF`.
+/// With `max_bytes` set, the upload is aborted once the file would grow past it.
+pub async fn copy_from_client<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    transfer_type: TransferType,
+    rate_limit: u64,
+    max_bytes: Option<u64>,
+    buffer_size: usize,
+    progress: Option<&mut Progress>,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let translation = match transfer_type {
+        TransferType::Ascii => Translation::ToLf { pending_cr: false },
+        TransferType::Image => Translation::None,
+    };
+    copy(
+        reader,
+        writer,
+        translation,
+        Throttle::new(rate_limit),
+        max_bytes,
+        buffer_size,
+        progress,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs chunks through a translation, as `copy` does with reads of the given sizes.
+    fn translate_chunks(mut translation: Translation, data: &[u8], chunk: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        for part in data.chunks(chunk) {
+            translation.translate(part, &mut out);
+        }
+        translation.finish(&mut out);
+        out
+    }
+
+    #[test]
+    fn converts_lf_to_crlf() {
+        let out = translate_chunks(Translation::ToCrlf { previous: 0 }, b"a\nb\r\nc\n", 64);
+        assert_eq!(out, b"a\r\nb\r\nc\r\n");
+    }
+
+    #[test]
+    fn converts_crlf_to_lf() {
+        let out = translate_chunks(Translation::ToLf { pending_cr: false }, b"a\r\nb\rc\r", 64);
+        assert_eq!(out, b"a\nb\rc\r");
+    }
+
+    #[test]
+    fn round_trips_across_chunk_boundaries() {
+        let text = b"first line\nsecond\r\n\nlast\rline\n";
+        for chunk in 1..text.len() {
+            let sent = translate_chunks(Translation::ToCrlf { previous: 0 }, text, chunk);
+            assert!(!sent.windows(2).any(|w| w[1] == b'\n' && w[0] != b'\r'));
+            let stored = translate_chunks(Translation::ToLf { pending_cr: false }, &sent, chunk);
+            assert_eq!(
+                stored, b"first line\nsecond\n\nlast\rline\n",
+                "chunk {chunk}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn ascii_upload_counts_received_bytes() {
+        let mut reader: &[u8] = b"a\r\nb\r\n";
+        let mut stored = Vec::new();
+        let bytes = copy_from_client(
+            &mut reader,
+            &mut stored,
+            TransferType::Ascii,
+            0,
+            None,
+            2,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(bytes, 6);
+        assert_eq!(stored, b"a\nb\n");
+    }
+
+    #[tokio::test]
+    async fn upload_stops_past_limit() {
+        let mut reader: &[u8] = &[0u8; 100];
+        let mut stored = Vec::new();
+        let error = copy_from_client(
+            &mut reader,
+            &mut stored,
+            TransferType::Image,
+            0,
+            Some(50),
+            16,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::FileTooLarge);
+        assert!(stored.len() <= 50);
+    }
+}
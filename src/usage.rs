@@ -0,0 +1,89 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Mutex;
+
+use crate::vfs::VirtualFs;
+
+/// Total size of files under the served root, which storage quotas are checked against.
+/// It's shared between sessions, so the root isn't walked for every upload. Uploads adjust it
+/// when they finish, and it's counted again periodically to notice changes made outside of the server.
+#[derive(Debug, Default)]
+pub struct DiskUsage {
+    counted: Mutex<Option<(u64, Instant)>>,
+}
+
+impl DiskUsage {
+    const RECOUNT_INTERVAL: Duration = Duration::from_secs(60);
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns size of the served root in bytes, counting it if the last count is too old.
+    pub async fn get(&self, vfs: &dyn VirtualFs) -> u64 {
+        // Lock is held while counting, so sessions asking at the same time share one walk.
+        let mut counted = self.counted.lock().await;
+        if let Some((bytes, counted_at)) = *counted
+            && counted_at.elapsed() < Self::RECOUNT_INTERVAL
+        {
+            return bytes;
+        }
+
+        let bytes = count(vfs).await;
+        *counted = Some((bytes, Instant::now()));
+        bytes
+    }
+
+    /// Accounts for a file that has changed size from `old` to `new` bytes.
+    /// Nothing is tracked until usage is needed for the first time.
+    pub async fn adjust(&self, old: u64, new: u64) {
+        if let Some((bytes, _)) = self.counted.lock().await.as_mut() {
+            *bytes = (*bytes + new).saturating_sub(old);
+        }
+    }
+}
+
+/// Sums sizes of all files under the root. Unreadable directories are skipped.
+async fn count(vfs: &dyn VirtualFs) -> u64 {
+    let mut total = 0;
+    let mut pending = vec![PathBuf::from("/")];
+    while let Some(dir) = pending.pop() {
+        let Ok(mut entries) = vfs.open_dir(&dir).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if entry.is_dir {
+                pending.push(dir.join(&entry.name));
+            } else {
+                total += entry.size;
+            }
+        }
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::vfs::DiskFs;
+
+    #[tokio::test]
+    async fn tracks_changes_between_counts() {
+        let root = tempfile::tempdir().unwrap();
+        let usage = DiskUsage::new();
+        let vfs = DiskFs::new(root.path());
+        assert_eq!(usage.get(&vfs).await, 0);
+
+        // Files appearing behind the server's back are seen only after a recount.
+        fs::write(root.path().join("outside"), [0u8; 10]).unwrap();
+        assert_eq!(usage.get(&vfs).await, 0);
+        usage.adjust(0, 25).await;
+        usage.adjust(5, 0).await;
+        assert_eq!(usage.get(&vfs).await, 20);
+    }
+}
@@ -0,0 +1,576 @@
+#[cfg(unix)]
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use std::{
+    io::{self, ErrorKind},
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+use async_trait::async_trait;
+use thiserror::Error;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncRead, AsyncSeekExt, AsyncWrite, SeekFrom},
+};
+
+pub type FileReader = Box<dyn AsyncRead + Send + Unpin>;
+pub type FileWriter = Box<dyn AsyncWrite + Send + Unpin>;
+pub type DirEntries = Box<dyn DirReader>;
+
+/// Information about a file or directory.
+#[derive(Debug, Clone)]
+pub struct FileInfo {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// Unix permission bits (e.g. `0o644`).
+    pub mode: u32,
+    /// Number of hard links.
+    pub nlink: u64,
+    /// Owner and group names, if backend knows them.
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    /// Numeric owner and group IDs, if backend knows them.
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub is_symlink: bool,
+    /// Where the link points to, if it's a symlink and the target can be shown to clients.
+    pub symlink_target: Option<String>,
+}
+
+/// Payload of errors returned when the served root itself is gone, as opposed to a file inside it.
+#[derive(Debug, Error)]
+#[error("served root directory is unavailable")]
+pub struct RootUnavailable;
+
+/// Checks if error was caused by the served root being unavailable.
+pub fn is_root_unavailable(error: &io::Error) -> bool {
+    error
+        .get_ref()
+        .is_some_and(|inner| inner.is::<RootUnavailable>())
+}
+
+/// Entries of a directory, produced one at a time so huge directories don't have to fit in memory.
+#[async_trait]
+pub trait DirReader: Send {
+    /// Returns the next entry, or `None` once all of them were read.
+    async fn next_entry(&mut self) -> io::Result<Option<FileInfo>>;
+}
+
+/// Entries that were already read into memory.
+struct BufferedEntries(std::vec::IntoIter<FileInfo>);
+
+#[async_trait]
+impl DirReader for BufferedEntries {
+    async fn next_entry(&mut self) -> io::Result<Option<FileInfo>> {
+        Ok(self.0.next())
+    }
+}
+
+/// Storage backend used by sessions. All paths are virtual and absolute, with `/` being the served root.
+#[async_trait]
+pub trait VirtualFs: Send + Sync {
+    /// Lists entries of a directory.
+    async fn read_dir(&self, path: &Path) -> io::Result<Vec<FileInfo>>;
+
+    /// Opens a directory to read its entries one by one.
+    /// By default the whole directory is read with `read_dir` first.
+    async fn open_dir(&self, path: &Path) -> io::Result<DirEntries> {
+        let entries = self.read_dir(path).await?;
+        Ok(Box::new(BufferedEntries(entries.into_iter())))
+    }
+
+    /// Returns information about a file or directory.
+    async fn metadata(&self, path: &Path) -> io::Result<FileInfo>;
+
+    /// Opens a file for reading, starting at the given offset.
+    async fn open(&self, path: &Path, offset: u64) -> io::Result<FileReader>;
+
+    /// Opens a file for writing at the given offset. Contents past the offset are discarded.
+    /// An offset of `0` creates the file or truncates an existing one.
+    async fn create(&self, path: &Path, offset: u64) -> io::Result<FileWriter>;
+
+    /// Creates a file for writing, failing if it already exists.
+    async fn create_new(&self, path: &Path) -> io::Result<FileWriter>;
+
+    /// Renames a file or directory.
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Removes a file.
+    async fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// Returns where the file is stored on local disk, if it is. Allows zero-copy downloads.
+    async fn local_path(&self, _path: &Path) -> Option<PathBuf> {
+        None
+    }
+
+    /// Sets Unix permission bits of a file.
+    async fn set_mode(&self, _path: &Path, _mode: u32) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "setting permissions is not supported",
+        ))
+    }
+
+    /// Sets modification time of a file.
+    async fn set_modified(&self, _path: &Path, _time: SystemTime) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "setting modification time is not supported",
+        ))
+    }
+
+    /// Returns free space in bytes available for files under the path.
+    async fn available_space(&self, _path: &Path) -> io::Result<u64> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "reporting free space is not supported",
+        ))
+    }
+}
+
+/// Default backend that serves files from a directory on disk.
+/// Paths can't escape the root with `..`, and by default not through symlinks either.
+#[derive(Debug, Clone)]
+pub struct DiskFs {
+    root: PathBuf,
+    follow_symlinks: bool,
+    #[cfg(unix)]
+    names: Arc<OwnerNames>,
+}
+
+/// Owner and group names from the system user database, remembered by ID
+/// so listings don't look them up again for every entry.
+#[cfg(unix)]
+#[derive(Debug, Default)]
+struct OwnerNames {
+    users: Mutex<HashMap<u32, String>>,
+    groups: Mutex<HashMap<u32, String>>,
+}
+
+#[cfg(unix)]
+impl OwnerNames {
+    async fn user(&self, uid: u32) -> String {
+        Self::cached(&self.users, uid, |uid| {
+            uzers::get_user_by_uid(uid).map(|u| u.name().to_string_lossy().to_string())
+        })
+        .await
+    }
+
+    async fn group(&self, gid: u32) -> String {
+        Self::cached(&self.groups, gid, |gid| {
+            uzers::get_group_by_gid(gid).map(|g| g.name().to_string_lossy().to_string())
+        })
+        .await
+    }
+
+    /// Returns a remembered name, or looks it up on the blocking thread pool, as the user
+    /// database may be backed by a network service. Numeric ID is used for unknown ones.
+    async fn cached(
+        names: &Mutex<HashMap<u32, String>>,
+        id: u32,
+        lookup: fn(u32) -> Option<String>,
+    ) -> String {
+        if let Some(name) = names.lock().unwrap().get(&id) {
+            return name.clone();
+        }
+        let name = tokio::task::spawn_blocking(move || lookup(id))
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| id.to_string());
+        names.lock().unwrap().insert(id, name.clone());
+        name
+    }
+}
+
+impl DiskFs {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        let root = root.into();
+        let root = std::fs::canonicalize(&root).unwrap_or(root);
+        Self {
+            root,
+            follow_symlinks: false,
+            #[cfg(unix)]
+            names: Arc::default(),
+        }
+    }
+
+    /// Allows symlinks inside the root to point anywhere, including outside of it.
+    pub fn follow_symlinks(mut self, follow: bool) -> Self {
+        self.follow_symlinks = follow;
+        self
+    }
+
+    /// Maps a virtual path to a real one inside the root. Checking it touches the disk,
+    /// so it's done on the blocking thread pool.
+    async fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        let fs = self.clone();
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || fs.resolve_blocking(&path)).await?
+    }
+
+    fn resolve_blocking(&self, path: &Path) -> io::Result<PathBuf> {
+        if !self.root.is_dir() {
+            return Err(io::Error::new(ErrorKind::NotFound, RootUnavailable));
+        }
+
+        let mut relative = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(part) => relative.push(part),
+                Component::ParentDir => {
+                    relative.pop();
+                }
+                _ => {}
+            }
+        }
+        let real = self.root.join(&relative);
+        if self.follow_symlinks {
+            return Ok(real);
+        }
+
+        // Symlinks may point outside of root, so every existing component is checked without
+        // following it. Dangling links are refused too, as writing through them would create
+        // their target wherever it is.
+        let outside = || io::Error::new(ErrorKind::PermissionDenied, "path is outside of root");
+        let mut current = self.root.clone();
+        for part in relative.components() {
+            let next = current.join(part);
+            match std::fs::symlink_metadata(&next) {
+                Ok(metadata) if metadata.is_symlink() => {
+                    let target = std::fs::canonicalize(&next).map_err(|_| outside())?;
+                    if !target.starts_with(&self.root) {
+                        return Err(outside());
+                    }
+                    current = target;
+                }
+                Ok(_) => current = next,
+                // Nothing below a missing component exists, so there are no more links to check.
+                Err(e) if e.kind() == ErrorKind::NotFound => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(real)
+    }
+
+    async fn file_info(&self, name: String, metadata: &std::fs::Metadata) -> FileInfo {
+        #[cfg(unix)]
+        let mode = metadata.permissions().mode();
+
+        #[cfg(unix)]
+        let (uid, gid) = (Some(metadata.uid()), Some(metadata.gid()));
+        #[cfg(unix)]
+        let (owner, group) = (
+            Some(self.names.user(metadata.uid()).await),
+            Some(self.names.group(metadata.gid()).await),
+        );
+
+        #[cfg(not(unix))]
+        let (uid, gid, owner, group) = (None, None, None, None);
+
+        #[cfg(unix)]
+        let nlink = metadata.nlink();
+
+        #[cfg(not(unix))]
+        let nlink = 1;
+
+        #[cfg(not(unix))]
+        let mode = if metadata.permissions().readonly() {
+            0o444 // r--r--r--
+        } else {
+            0o644 // rw-r--r--
+        };
+
+        FileInfo {
+            name,
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+            mode,
+            nlink,
+            owner,
+            group,
+            uid,
+            gid,
+            is_symlink: metadata.is_symlink(),
+            symlink_target: None,
+        }
+    }
+
+    /// Turns a symlink target into something safe to show: relative targets as is,
+    /// absolute ones as virtual paths. Targets outside of root are hidden.
+    fn virtual_target(&self, target: &Path) -> Option<String> {
+        if target.is_relative() {
+            return Some(target.to_string_lossy().to_string());
+        }
+        target
+            .strip_prefix(&self.root)
+            .ok()
+            .map(|p| format!("/{}", p.to_string_lossy()))
+    }
+
+    async fn create_parent(real: &Path) -> io::Result<()> {
+        if let Some(parent) = real.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Directory of `DiskFs`, read lazily.
+struct DiskDirEntries {
+    fs: DiskFs,
+    entries: fs::ReadDir,
+}
+
+#[async_trait]
+impl DirReader for DiskDirEntries {
+    async fn next_entry(&mut self) -> io::Result<Option<FileInfo>> {
+        while let Some(entry) = self.entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            // Entries that can't be read are skipped rather than failing the whole listing.
+            let Ok(metadata) = fs::symlink_metadata(entry.path()).await else {
+                continue;
+            };
+
+            let mut info = self.fs.file_info(name, &metadata).await;
+            if info.is_symlink {
+                info.symlink_target = fs::read_link(entry.path())
+                    .await
+                    .ok()
+                    .and_then(|t| self.fs.virtual_target(&t));
+            }
+            return Ok(Some(info));
+        }
+        Ok(None)
+    }
+}
+
+#[async_trait]
+impl VirtualFs for DiskFs {
+    async fn read_dir(&self, path: &Path) -> io::Result<Vec<FileInfo>> {
+        let mut entries = self.open_dir(path).await?;
+        let mut infos = Vec::new();
+        while let Some(info) = entries.next_entry().await? {
+            infos.push(info);
+        }
+        Ok(infos)
+    }
+
+    async fn open_dir(&self, path: &Path) -> io::Result<DirEntries> {
+        let entries = fs::read_dir(self.resolve(path).await?).await?;
+        Ok(Box::new(DiskDirEntries {
+            fs: self.clone(),
+            entries,
+        }))
+    }
+
+    async fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        let real = self.resolve(path).await?;
+        let metadata = fs::metadata(&real).await?;
+        let name = real
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Ok(self.file_info(name, &metadata).await)
+    }
+
+    async fn open(&self, path: &Path, offset: u64) -> io::Result<FileReader> {
+        let mut file = File::open(self.resolve(path).await?).await?;
+        if offset > 0 {
+            file.seek(SeekFrom::Start(offset)).await?;
+        }
+        Ok(Box::new(file))
+    }
+
+    async fn create(&self, path: &Path, offset: u64) -> io::Result<FileWriter> {
+        let real = self.resolve(path).await?;
+        if offset == 0 {
+            Self::create_parent(&real).await?;
+            return Ok(Box::new(File::create(&real).await?));
+        }
+
+        let mut file = OpenOptions::new().write(true).open(&real).await?;
+        let size = file.metadata().await?.len();
+        if offset > size {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "offset is past the end of file",
+            ));
+        }
+
+        file.set_len(offset).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        Ok(Box::new(file))
+    }
+
+    async fn create_new(&self, path: &Path) -> io::Result<FileWriter> {
+        let real = self.resolve(path).await?;
+        Self::create_parent(&real).await?;
+        Ok(Box::new(File::create_new(&real).await?))
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(self.resolve(from).await?, self.resolve(to).await?).await
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(self.resolve(path).await?).await
+    }
+
+    async fn local_path(&self, path: &Path) -> Option<PathBuf> {
+        self.resolve(path).await.ok()
+    }
+
+    #[cfg(unix)]
+    async fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        fs::set_permissions(
+            self.resolve(path).await?,
+            std::fs::Permissions::from_mode(mode),
+        )
+        .await
+    }
+
+    #[cfg(unix)]
+    async fn available_space(&self, path: &Path) -> io::Result<u64> {
+        use std::{ffi::CString, mem::MaybeUninit, os::unix::ffi::OsStringExt};
+
+        let real = self.resolve(path).await?;
+        let path = CString::new(real.into_os_string().into_vec())
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "path contains NUL byte"))?;
+        tokio::task::spawn_blocking(move || {
+            let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+            // SAFETY: path is NUL terminated and `stat` is large enough for the result.
+            if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: `statvfs` succeeded, so it has filled `stat`.
+            let stat = unsafe { stat.assume_init() };
+            // Field types differ between platforms.
+            #[allow(clippy::unnecessary_cast)]
+            Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+        })
+        .await?
+    }
+
+    async fn set_modified(&self, path: &Path, time: SystemTime) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(self.resolve(path).await?)
+            .await?
+            .into_std()
+            .await;
+        tokio::task::spawn_blocking(move || file.set_modified(time)).await?
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// Served root next to a directory outside of it.
+    fn dirs() -> (TempDir, PathBuf, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let outside = dir.path().join("outside");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::create_dir(&outside).unwrap();
+        std::fs::write(outside.join("secret"), "secret").unwrap();
+        (dir, root, outside)
+    }
+
+    #[tokio::test]
+    async fn parent_segments_stay_in_root() {
+        let (_dir, root, _) = dirs();
+        let fs = DiskFs::new(&root);
+        let error = fs
+            .metadata(Path::new("/../outside/secret"))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn refuses_links_leading_outside() {
+        let (_dir, root, outside) = dirs();
+        symlink(&outside, root.join("out")).unwrap();
+        symlink(outside.join("secret"), root.join("secret")).unwrap();
+        let fs = DiskFs::new(&root);
+
+        for path in ["/out/secret", "/secret", "/out"] {
+            let error = fs.open(Path::new(path), 0).await.err().unwrap();
+            assert_eq!(error.kind(), ErrorKind::PermissionDenied, "{path}");
+        }
+        let error = fs.create(Path::new("/out/new"), 0).await.err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        assert!(!outside.join("new").exists());
+    }
+
+    #[tokio::test]
+    async fn refuses_writing_through_dangling_links() {
+        let (_dir, root, outside) = dirs();
+        symlink(outside.join("new"), root.join("x")).unwrap();
+        let fs = DiskFs::new(&root);
+
+        let error = fs.create(Path::new("/x"), 0).await.err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        let error = fs.create_new(Path::new("/x")).await.err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        assert!(!outside.join("new").exists());
+    }
+
+    #[tokio::test]
+    async fn allows_links_inside_root() {
+        let (_dir, root, _) = dirs();
+        std::fs::create_dir(root.join("dir")).unwrap();
+        std::fs::write(root.join("dir/file"), "data").unwrap();
+        symlink(root.join("dir"), root.join("link")).unwrap();
+        symlink("dir/file", root.join("relative")).unwrap();
+        let fs = DiskFs::new(&root);
+
+        assert_eq!(fs.metadata(Path::new("/link/file")).await.unwrap().size, 4);
+        assert_eq!(fs.metadata(Path::new("/relative")).await.unwrap().size, 4);
+        fs.create(Path::new("/link/new"), 0).await.unwrap();
+        assert!(root.join("dir/new").exists());
+    }
+
+    #[tokio::test]
+    async fn follows_links_outside_when_allowed() {
+        let (_dir, root, outside) = dirs();
+        symlink(&outside, root.join("out")).unwrap();
+        let fs = DiskFs::new(&root).follow_symlinks(true);
+
+        assert_eq!(fs.metadata(Path::new("/out/secret")).await.unwrap().size, 6);
+    }
+
+    #[tokio::test]
+    async fn owner_names_are_looked_up_once() {
+        let (_dir, root, _) = dirs();
+        for name in ["a", "b", "c"] {
+            std::fs::write(root.join(name), name).unwrap();
+        }
+        let fs = DiskFs::new(&root);
+        let entries = fs.read_dir(Path::new("/")).await.unwrap();
+
+        let uid = uzers::get_current_uid();
+        let expected = uzers::get_user_by_uid(uid)
+            .map(|u| u.name().to_string_lossy().to_string())
+            .unwrap_or_else(|| uid.to_string());
+        assert!(entries.iter().all(|e| e.owner.as_ref() == Some(&expected)));
+        assert_eq!(fs.names.users.lock().unwrap().len(), 1);
+        assert_eq!(fs.names.groups.lock().unwrap().len(), 1);
+    }
+}